@@ -9,16 +9,48 @@ use sensevoice_rs::{fsmn_vad::VADXOptions, SenseVoiceSmall};
 use serde::Deserialize;
 use tokio_stream::wrappers::ReceiverStream;
 
-use crate::{AIModel, ProcessAudio, ShutdownMessages, AsrText, ASR};
+use crate::huggingface::ModelType;
+use crate::llm::queue::{JobKind, QueueFairness, Worker};
+use crate::models::{AudioFormat, ModelDetails};
+use crate::shutdown::HandleGuard;
+use crate::{AIModel, Ping, ProcessAudio, ShutdownMessages, AsrText, ASR};
 
 #[derive(Debug, Clone, Default, Deserialize)]
 pub struct SimpleASRConfig {
     pub modle_path: String,
     pub modle_name: String,
+    /// Sample rate the model was trained on, reported to clients.
+    #[serde(default = "default_sample_rate")]
+    pub sample_rate: u32,
+    #[serde(default = "default_channels")]
+    pub channels: u16,
+    /// Uploads longer than this are rejected before transcription.
+    pub max_duration_secs: Option<f32>,
+}
+
+fn default_sample_rate() -> u32 {
+    16000
+}
+
+fn default_channels() -> u16 {
+    1
 }
 
 pub struct SimpleASR {
     handle: Arc<SenseVoiceSmall>,
+    /// Held for the whole inference.
+    destroyed: HandleGuard,
+    /// Inference blocks for seconds, on the actix thread it held up every
+    /// actor and the watchdog pings.
+    worker: Worker,
+    details: ModelDetails,
+}
+
+impl SimpleASR {
+    /// Describes this instance for the models endpoints, `instances` is left at 0.
+    pub fn model_details(&self) -> ModelDetails {
+        self.details.clone()
+    }
 }
 
 impl Actor for SimpleASR {
@@ -31,29 +63,42 @@ impl actix::Handler<ProcessAudio> for SimpleASR {
         let (tx, rx) = tokio::sync::mpsc::channel::<AsrText>(64);
 
         let handle_clone = self.handle.clone();
-        actix_web::rt::spawn(async move {
-            let allseg = match msg {
-                ProcessAudio::FilePath(audio_path) => handle_clone
-                    .infer_file(audio_path)
-                    .expect("Infer file failed"),
-                ProcessAudio::Buffer(read) => {
-                    // TODO: sensevoice-rs not support reader now, so read all!
-                    let mut wav_reader =
-                        WavReader::new(read).expect("Should give me wave reader but not!");
-                    let content = wav_reader
-                        .samples()
-                        .filter_map(|x| x.ok())
-                        .collect::<Vec<i16>>();
-                    handle_clone
-                        .infer_vec(content, 16000)
-                        .expect("Infer vec failed")
+        let destroyed = self.destroyed.clone();
+        let dispatched = self.worker.dispatch(JobKind::NonStreaming, move || {
+            let npu = futures::executor::block_on(crate::npu::acquire());
+            let allseg = destroyed.run(|| {
+                match msg {
+                    ProcessAudio::FilePath(audio_path) => handle_clone
+                        .infer_file(audio_path)
+                        .expect("Infer file failed"),
+                    ProcessAudio::Buffer(read) => {
+                        // TODO: sensevoice-rs not support reader now, so read all!
+                        let mut wav_reader =
+                            WavReader::new(read).expect("Should give me wave reader but not!");
+                        let content = wav_reader
+                            .samples()
+                            .filter_map(|x| x.ok())
+                            .collect::<Vec<i16>>();
+                        handle_clone
+                            .infer_vec(content, 16000)
+                            .expect("Infer vec failed")
+                    }
                 }
+            });
+            drop(npu);
+            // Shutdown won the race, dropping tx ends the stream.
+            let Some(allseg) = allseg else {
+                return;
             };
             for seg in allseg {
                 // TODO: Maybe someday should have good error handling
-                let _ = tx.send(AsrText::SenseVoice(seg)).await;
+                let _ = tx.blocking_send(AsrText::SenseVoice(seg));
             }
         });
+        // The worker died with a panicking inference
+        if !dispatched {
+            return Err(());
+        }
 
         // 將 Receiver 轉換為 Stream
         let stream = ReceiverStream::new(rx);
@@ -65,12 +110,23 @@ impl actix::Handler<ShutdownMessages> for SimpleASR {
     type Result = Result<(), ()>;
 
     fn handle(&mut self, _msg: ShutdownMessages, _ctx: &mut Self::Context) -> Self::Result {
-        // TODO: Maybe someday should have good error handling
-        let _ = self.handle.destroy();
+        // Wait for any in-flight inference before destroying
+        self.destroyed.destroy(|| {
+            // TODO: Maybe someday should have good error handling
+            let _ = self.handle.destroy();
+        });
         Ok(())
     }
 }
 
+impl actix::Handler<Ping> for SimpleASR {
+    type Result = bool;
+
+    fn handle(&mut self, _: Ping, _: &mut Self::Context) -> Self::Result {
+        !self.destroyed.is_destroyed() && self.worker.alive()
+    }
+}
+
 impl ASR for SimpleASR {}
 
 impl AIModel for SimpleASR {
@@ -84,6 +140,23 @@ impl AIModel for SimpleASR {
             SenseVoiceSmall::init(&config.modle_path, VADXOptions::default())
                 .map_err(|_| "Load model error")?,
         );
-        Ok(SimpleASR { handle })
+        let mut details = ModelDetails::new(&config.modle_name, &config.modle_path, ModelType::ASR);
+        details.revision = hf_hub::api::sync::Api::new()
+            .and_then(|api| api.model(config.modle_path.clone()).info())
+            .ok()
+            .map(|info| info.sha);
+        details.audio = Some(AudioFormat {
+            sample_rate: config.sample_rate,
+            channels: config.channels,
+            sample_format: "s16".to_owned(),
+            max_duration_secs: config.max_duration_secs,
+            upload_formats: vec!["wav".to_owned()],
+        });
+        Ok(SimpleASR {
+            handle,
+            destroyed: HandleGuard::default(),
+            worker: Worker::spawn(&config.modle_name, QueueFairness::Fifo)?,
+            details,
+        })
     }
 }
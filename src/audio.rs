@@ -1,14 +1,24 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, path::Path};
 
 use actix::Recipient;
-use actix_multipart::form::{tempfile::TempFile, text::Text, MultipartForm};
-use actix_web::{post, HttpResponse, Responder};
+use actix_multipart::{
+    form::{tempfile::TempFile, text::Text, MultipartForm},
+    MultipartError,
+};
+use actix_web::{error::InternalError, post, HttpRequest, HttpResponse, ResponseError};
+use hound::{SampleFormat, WavReader};
 use futures::StreamExt;
 use rand::seq::IndexedRandom;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 
-use crate::{OpenAiError, ProcessAudio};
+use crate::{
+    error::ApiError,
+    huggingface::ModelType,
+    metrics::Metrics,
+    models::{model_not_found, LoadFailures, ModelDetails, ModelNotFound},
+    OpenAiError, ProcessAudio,
+};
 
 #[derive(Deserialize, Serialize, utoipa::ToSchema)]
 pub struct TranscriptionsResponse {
@@ -21,9 +31,36 @@ struct UploadForm {
     file: TempFile,
 }
 
+/// Turns a broken multipart body, e.g. an upload cut off mid-transfer, into an
+/// OpenAI style 400 instead of actix's plain text one.
+pub fn multipart_error(err: MultipartError, _req: &HttpRequest) -> actix_web::Error {
+    let response = ApiError::InvalidUpload(err.to_string()).error_response();
+    InternalError::from_response(err, response).into()
+}
+
+/// Decodes every sample so a file truncated inside its data chunk is caught here
+/// rather than handed to the model. Returns the duration in seconds.
+fn check_wav(path: &Path) -> Result<f32, String> {
+    let mut reader = WavReader::open(path).map_err(|e| format!("Not a readable WAV file: {}", e))?;
+    let spec = reader.spec();
+    let decoded = match spec.sample_format {
+        SampleFormat::Int => reader.samples::<i32>().try_fold(0usize, |n, s| s.map(|_| n + 1)),
+        SampleFormat::Float => reader.samples::<f32>().try_fold(0usize, |n, s| s.map(|_| n + 1)),
+    };
+    let samples = decoded.map_err(|e| format!("WAV file is truncated or corrupt: {}", e))?;
+    let duration = samples as f32 / (spec.sample_rate as f32 * spec.channels.max(1) as f32);
+    if duration <= 0.0 {
+        return Err("WAV file contains no audio.".to_owned());
+    }
+    Ok(duration)
+}
+
 #[utoipa::path(
     responses(
-        (status = OK, description = "Success", body = TranscriptionsResponse, content_type = "application/json")
+        (status = OK, description = "Success", body = TranscriptionsResponse, content_type = "application/json"),
+        (status = BAD_REQUEST, description = "Incomplete or empty upload", body = OpenAiError, content_type = "application/json"),
+        (status = NOT_FOUND, description = "Unknown model", body = ModelNotFound, content_type = "application/json"),
+        (status = SERVICE_UNAVAILABLE, description = "The model failed to load", body = OpenAiError, content_type = "application/json")
     ),
     security(
         ("api_key" = [])
@@ -33,22 +70,30 @@ struct UploadForm {
 pub async fn audio_transcriptions(
     form: MultipartForm<UploadForm>,
     asr_pool: actix_web::web::Data<HashMap<String, Vec<Recipient<ProcessAudio>>>>,
-) -> impl Responder {
-    println!("{:?}", form.file);
-    println!("{:?}", form.model);
+    model_registry: actix_web::web::Data<HashMap<String, ModelDetails>>,
+    load_failures: actix_web::web::Data<LoadFailures>,
+    metrics: actix_web::web::Data<Metrics>,
+) -> Result<HttpResponse, ApiError> {
+    metrics.count_transcription_request();
 
     let Some(asr_pool) = asr_pool.get(&form.model.0) else {
-        return HttpResponse::BadRequest().json(OpenAiError {
-            message: format!(
-                "The model {} does not exist or you do not have access to it.",
-                form.model.0
-            ),
-            code: "model_not_found".to_owned(),
-            r#type: "invalid_request_error".to_owned(),
-            param: None,
-        });
+        return Err(load_failures
+            .error(&form.model.0)
+            .unwrap_or_else(|| model_not_found(&form.model.0, Some(ModelType::ASR), &model_registry)));
     };
 
+    let duration = check_wav(form.file.file.path()).map_err(ApiError::InvalidAudio)?;
+    let max_duration = model_registry
+        .get(&form.model.0)
+        .and_then(|details| details.audio.as_ref())
+        .and_then(|audio| audio.max_duration_secs);
+    if let Some(max_duration) = max_duration.filter(|max| duration > *max) {
+        return Err(ApiError::InvalidAudio(format!(
+            "Audio is {:.1}s long, {} accepts at most {:.1}s.",
+            duration, form.model.0, max_duration
+        )));
+    }
+
     let mut rng = rand::rng();
     let asr = asr_pool.choose(&mut rng).unwrap();
     let path = form.file.file.as_ref().to_string_lossy().to_string();
@@ -62,25 +107,69 @@ pub async fn audio_transcriptions(
 
             let transcription_parts: Vec<String> = sse_stream.collect().await;
             let full_transcription = transcription_parts.join("");
-            HttpResponse::Ok().json(json!({ "text": full_transcription }))
+            Ok(HttpResponse::Ok().json(json!({ "text": full_transcription })))
         }
-        Ok(Ok(Err(e))) => HttpResponse::InternalServerError().json(OpenAiError {
-            message: format!("Internal processing error: {:?}", e),
-            code: "processing_error".to_owned(),
-            r#type: "internal_error".to_owned(),
-            param: None,
-        }),
-        Err(_timeout) => HttpResponse::UnavailableForLegalReasons().json(OpenAiError {
-            message: format!("Server Busy."),
-            code: "server_".to_owned(),
-            r#type: "internal_error".to_owned(),
-            param: None,
-        }),
-        Ok(Err(e)) => HttpResponse::UnavailableForLegalReasons().json(OpenAiError {
-            message: format!("Internal server error:{}", e),
-            code: "server_".to_owned(),
-            r#type: "internal_error".to_owned(),
-            param: None,
-        }),
+        Ok(Ok(Err(e))) => Err(ApiError::Processing(format!("{:?}", e))),
+        Err(_timeout) => Err(ApiError::InstanceBusy),
+        Ok(Err(e)) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use hound::{WavSpec, WavWriter};
+
+    use super::*;
+
+    /// A 16 kHz mono WAV with `samples` samples, unique per test.
+    fn write_wav(name: &str, samples: usize) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("llmserver-{}-{}.wav", std::process::id(), name));
+        let spec = WavSpec {
+            channels: 1,
+            sample_rate: 16000,
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int,
+        };
+        let mut writer = WavWriter::create(&path, spec).unwrap();
+        for i in 0..samples {
+            writer.write_sample((i % 100) as i16).unwrap();
+        }
+        writer.finalize().unwrap();
+        path
+    }
+
+    #[test]
+    fn complete_wav() {
+        let path = write_wav("complete", 8000);
+        assert_eq!(check_wav(&path), Ok(0.5));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn truncated_wav() {
+        let path = write_wav("truncated", 8000);
+        // The header still announces 8000 samples
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &bytes[..bytes.len() / 2 + 1]).unwrap();
+        let error = check_wav(&path).unwrap_err();
+        assert!(error.contains("truncated"), "{}", error);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn empty_wav() {
+        let path = write_wav("empty", 0);
+        assert_eq!(check_wav(&path), Err("WAV file contains no audio.".to_owned()));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn not_a_wav() {
+        let path = std::env::temp_dir().join(format!("llmserver-{}-garbage.wav", std::process::id()));
+        std::fs::write(&path, b"definitely not RIFF").unwrap();
+        assert!(check_wav(&path).unwrap_err().starts_with("Not a readable WAV file"));
+        std::fs::remove_file(path).unwrap();
     }
 }
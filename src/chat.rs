@@ -1,15 +1,46 @@
-use actix::Recipient;
+use actix::MailboxError;
 use actix_web::{
+    http::header::{self, Header, CONTENT_ENCODING, VARY},
     post,
     web::{self, Json},
-    HttpResponse, Responder,
+    HttpRequest, HttpResponse,
 };
 use futures::StreamExt;
 use rand::seq::IndexedRandom;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, time::SystemTime};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
+};
 
-use crate::{Content, Message, OpenAiError, ProcessMessages, Role};
+use crate::{
+    compress::{accepts_gzip, gzip_stream},
+    error::ApiError,
+    huggingface::ModelType,
+    idempotency::{IdempotencyCache, Lookup},
+    llm::{
+        simple::{EmptyOutput, SimpleLLMConfig},
+        think::ReasoningSplitter,
+    },
+    metrics::Metrics,
+    models::{model_not_found, LoadFailures, ModelDetails, ModelNotFound},
+    normalize::{trim_incomplete_sentence, OutputNormalization},
+    response::ObjectType,
+    slo::Priority,
+    Content,
+    HiddenStates,
+    LlmInstance,
+    Message,
+    OpenAiError,
+    ProcessMessages,
+    Restarter,
+    Role,
+    ServerConfig,
+};
 
 #[derive(Debug, Clone, Deserialize, Serialize, utoipa::ToSchema)]
 pub struct Delta {
@@ -86,6 +117,36 @@ pub struct ChatCompletionsRequest {
     pub tools: Option<Vec<Tool>>,
     pub tool_choice: Option<ToolChoice>,
     pub metadata: Option<HashMap<String, String>>,
+    /// Non-standard: script/width conversion applied to the generated text.
+    pub output_normalization: Option<OutputNormalization>,
+    /// Non-standard: return the prompt token ids to help diagnose templating.
+    pub debug: Option<bool>,
+    /// Non-standard: return the prompt's last hidden layer instead of generating text.
+    pub hidden_states: Option<bool>,
+    /// Non-standard: generation timeout in seconds, overrides the model default.
+    pub timeout: Option<f32>,
+    /// Non-standard test aid, needs `--debug-replay-stream`: generate the whole
+    /// response, then stream its chunks this many milliseconds apart.
+    pub replay_interval_ms: Option<u64>,
+    /// Non-standard: named prompt cache to load, or to save from this prompt
+    /// when the model has none by that name yet.
+    pub prompt_cache: Option<String>,
+    /// Non-standard: add each chunk's character offset and timing when streaming.
+    pub stream_offsets: Option<bool>,
+    /// Non-standard: let a reasoning model think or skip it for this request.
+    pub think: Option<bool>,
+    /// Non-standard: process the whole prompt without any prompt cache.
+    pub bypass_prompt_cache: Option<bool>,
+    /// Non-standard: when streaming, strip think tags and tag each chunk's `kind`
+    /// as `reasoning` or `content`.
+    pub stream_reasoning: Option<bool>,
+    /// Non-standard: run on an instance with at least this many NPU cores.
+    pub npu_cores: Option<u32>,
+    /// Non-standard: when a non-streaming response stops at a length limit,
+    /// drop the unfinished sentence at its end.
+    pub trim_incomplete: Option<bool>,
+    /// Non-standard: `low` requests are the first rejected under `--slo-target-ms`.
+    pub priority: Option<Priority>,
 }
 
 #[derive(Deserialize, Serialize, utoipa::ToSchema)]
@@ -98,9 +159,20 @@ pub enum FinishReason {
     InternalError,
 }
 
+/// Which part of a reasoning model's output a streamed chunk belongs to.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, utoipa::ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ChunkKind {
+    Reasoning,
+    Content,
+}
+
 #[derive(Deserialize, Serialize, utoipa::ToSchema)]
 pub struct Choice {
     pub index: i32,
+    /// Non-standard, only on chunks of `stream_reasoning` requests.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kind: Option<ChunkKind>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub delta: Option<Message>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -116,76 +188,540 @@ pub struct Usage {
     pub total_tokens: i32,
 }
 
+/// Non-standard per-chunk detail for `stream_offsets` requests.
+#[derive(Deserialize, Serialize, utoipa::ToSchema)]
+pub struct TokenOffset {
+    /// Same text as the chunk's content.
+    pub text: String,
+    /// Characters of output sent before this token.
+    pub offset: usize,
+    /// Milliseconds since the request was received.
+    pub elapsed_ms: u64,
+}
+
 #[derive(Deserialize, Serialize, utoipa::ToSchema)]
 pub struct ChatCompletionsResponse {
     pub id: String,
-    pub object: String,
+    #[schema(value_type = String)]
+    pub object: ObjectType,
     pub created: u64,
     pub choices: Vec<Choice>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub usage: Option<Usage>,
+    /// Changes only when the model file or its generation config does.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system_fingerprint: Option<String>,
+    /// Only present for `debug` requests, on the first chunk when streaming.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt_token_ids: Option<Vec<u32>>,
+    /// Only present for `hidden_states` requests.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hidden_states: Option<HiddenStates>,
+    /// Only present on content chunks of `stream_offsets` requests.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<TokenOffset>,
+}
+
+impl ChatCompletionsResponse {
+    pub fn completion(id: String, created: u64, choices: Vec<Choice>) -> Self {
+        ChatCompletionsResponse {
+            id,
+            object: ObjectType::ChatCompletion,
+            created,
+            choices,
+            usage: None,
+            system_fingerprint: None,
+            prompt_token_ids: None,
+            hidden_states: None,
+            token: None,
+        }
+    }
+
+    pub fn chunk(id: String, created: u64, choices: Vec<Choice>) -> Self {
+        ChatCompletionsResponse {
+            object: ObjectType::ChatCompletionChunk,
+            ..Self::completion(id, created, choices)
+        }
+    }
+}
+
+/// Joins a generation for a non-streaming response, stopping once `limit` bytes
+/// are buffered. Returns the text and whether it was cut short.
+async fn collect_output(
+    mut stream: impl futures::Stream<Item = String> + Unpin,
+    limit: usize,
+) -> (String, bool) {
+    let mut content = String::new();
+    while let Some(chunk) = stream.next().await {
+        let room = limit - content.len();
+        if chunk.len() > room {
+            let mut end = room;
+            while !chunk.is_char_boundary(end) {
+                end -= 1;
+            }
+            content.push_str(&chunk[..end]);
+            // Dropping the stream lets the callback stop forwarding the runaway output
+            return (content, true);
+        }
+        content.push_str(&chunk);
+    }
+    (content, false)
+}
+
+/// Splits a streamed piece into parts of at most `max_bytes` on char
+/// boundaries, so one huge token or runaway repeat cannot bloat a single SSE
+/// frame. A char longer than `max_bytes` still goes out whole.
+fn split_chunk(content: String, max_bytes: usize) -> Vec<String> {
+    if content.len() <= max_bytes {
+        return vec![content];
+    }
+    let mut parts = Vec::new();
+    let mut rest = content.as_str();
+    while !rest.is_empty() {
+        let mut end = max_bytes.min(rest.len());
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        if end == 0 {
+            end = rest.chars().next().map_or(rest.len(), char::len_utf8);
+        }
+        let (part, tail) = rest.split_at(end);
+        parts.push(part.to_owned());
+        rest = tail;
+    }
+    parts
+}
+
+/// Renames top-level body fields per `--field-alias`. A field already sent
+/// under its real name wins over its alias.
+fn apply_field_aliases(body: &mut serde_json::Value, aliases: &HashMap<String, String>) {
+    let Some(fields) = body.as_object_mut() else {
+        return;
+    };
+    for (alias, field) in aliases {
+        if let Some(value) = fields.remove(alias) {
+            fields.entry(field.clone()).or_insert(value);
+        }
+    }
+}
+
+/// Logs a dead instance and asks for a restart when that is enabled.
+fn dead_instance(model: &str, restarter: &Restarter) -> ApiError {
+    let reason = format!("Dead instance of {} detected", model);
+    println!("{}", reason);
+    restarter.request(reason);
+    ApiError::DeadInstance
+}
+
+/// The `finish_reason` of a completion that ended. Empty or whitespace-only
+/// output is a model error unless a timeout cut it off.
+fn finish_reason(produced_text: bool, length_limited: bool, timed_out: bool) -> FinishReason {
+    if timed_out || (produced_text && length_limited) {
+        FinishReason::Length
+    } else if produced_text {
+        FinishReason::Stop
+    } else {
+        FinishReason::ModelError
+    }
+}
+
+/// Whether a collected completion is empty or only whitespace, an error when
+/// the model's `empty_output` says so. Hidden state requests generate no text
+/// by design.
+fn empty_output(content: &str, has_hidden_states: bool, setting: EmptyOutput) -> Result<bool, ApiError> {
+    let empty = !has_hidden_states && content.trim().is_empty();
+    if empty && setting == EmptyOutput::Error {
+        return Err(ApiError::EmptyOutput);
+    }
+    Ok(empty)
+}
+
+/// Appends the empty piece that closes a stream unless the model sent one.
+fn close_stream(stream: impl futures::Stream<Item = String>) -> impl futures::Stream<Item = String> {
+    let closed = Arc::new(AtomicBool::new(false));
+    let seen = closed.clone();
+    stream
+        .inspect(move |piece| {
+            if piece.is_empty() {
+                seen.store(true, Ordering::Relaxed);
+            }
+        })
+        .chain(
+            futures::stream::once(async move { (!closed.load(Ordering::Relaxed)).then(String::new) })
+                .filter_map(futures::future::ready),
+        )
+}
+
+/// Follows a stream's pieces to give its closing chunk a `finish_reason`.
+#[derive(Debug, Default)]
+struct StreamFinish {
+    produced_text: bool,
+}
+
+impl StreamFinish {
+    /// The `finish_reason` of the chunk carrying `content`, set on the empty closing piece.
+    fn piece(&mut self, content: &str, length_limited: bool, timed_out: bool) -> Option<FinishReason> {
+        self.produced_text |= !content.trim().is_empty();
+        content.is_empty().then(|| finish_reason(self.produced_text, length_limited, timed_out))
+    }
+}
+
+/// True when the client ranks `text/plain` above JSON in its `Accept` header.
+fn wants_plain_text(req: &HttpRequest) -> bool {
+    header::Accept::parse(req)
+        .ok()
+        .and_then(|accept| accept.ranked().into_iter().next())
+        .is_some_and(|mime| mime.essence_str() == "text/plain")
 }
 
 #[utoipa::path(
     request_body = ChatCompletionsRequest,
+    params(
+        ("Idempotency-Key" = Option<String>, Header, description = "Replay the cached response for a repeated key, see --idempotency-ttl. Ignored for streaming requests, a retry while the first request runs gets a 409")
+    ),
     responses(
-        (status = OK, description = "Success", body = ChatCompletionsResponse, content_type = "application/json")
+        (status = OK, description = "Success", body = ChatCompletionsResponse, content_type = "application/json"),
+        (status = OK, description = "Completion text only, non-streaming with `Accept: text/plain`", body = String, content_type = "text/plain"),
+        (status = NOT_FOUND, description = "Unknown model", body = ModelNotFound, content_type = "application/json"),
+        (status = SERVICE_UNAVAILABLE, description = "The model failed to load", body = OpenAiError, content_type = "application/json")
     ),
     security(
         ("api_key" = [])
     ),
 )]
 #[post("/chat/completions")]
+#[allow(clippy::too_many_arguments)] // one extractor per piece of shared state
 pub async fn chat_completions(
-    body: Json<ChatCompletionsRequest>,
-    llm_pool: web::Data<HashMap<String, Vec<Recipient<ProcessMessages>>>>,
-) -> impl Responder {
+    req: HttpRequest,
+    body: Json<serde_json::Value>,
+    llm_pool: web::Data<HashMap<String, Vec<LlmInstance>>>,
+    llm_configs: web::Data<HashMap<String, SimpleLLMConfig>>,
+    server_config: web::Data<ServerConfig>,
+    idempotency: web::Data<IdempotencyCache>,
+    metrics: web::Data<Metrics>,
+    model_registry: web::Data<HashMap<String, ModelDetails>>,
+    load_failures: web::Data<LoadFailures>,
+    restarter: web::Data<Restarter>,
+) -> Result<HttpResponse, ApiError> {
     let id = "123".to_owned(); // Todo: 要改從資料庫拿
+    let received = Instant::now();
     let created = SystemTime::now();
     let created = created
         .duration_since(std::time::UNIX_EPOCH)
         .expect("Time went backwards")
         .as_secs();
 
+    let metrics = metrics.into_inner();
+    metrics.count_chat_request();
+    let mut body = body.into_inner();
+    apply_field_aliases(&mut body, &server_config.field_aliases);
+    let body: ChatCompletionsRequest =
+        serde_json::from_value(body).map_err(|e| ApiError::InvalidBody(e.to_string()))?;
+
+    let plain_text = wants_plain_text(&req);
+    // Streams are never cached, their key is ignored
+    let idempotency_key = if body.stream.unwrap_or(false) {
+        None
+    } else {
+        idempotency.key_for(&req, &body, plain_text)
+    };
+    let idempotency_key = match idempotency_key {
+        Some((key, fingerprint)) => match idempotency.lookup(key, fingerprint) {
+            Lookup::Hit(response) => return Ok(response),
+            Lookup::Mismatch => return Err(ApiError::IdempotencyKeyReused),
+            Lookup::InProgress => return Err(ApiError::IdempotencyKeyInProgress),
+            Lookup::Miss(reservation) => Some(reservation),
+        },
+        None => None,
+    };
+
     let Some(llm_pool) = llm_pool.get(&body.model) else {
-        return HttpResponse::BadRequest().json(OpenAiError {
-            message: format!(
-                "The model {} does not exist or you do not have access to it.",
-                body.model
-            ),
-            code: "model_not_found".to_owned(),
-            r#type: "invalid_request_error".to_owned(),
-            param: None,
+        return Err(load_failures
+            .error(&body.model)
+            .unwrap_or_else(|| model_not_found(&body.model, Some(ModelType::LLM), &model_registry)));
+    };
+    if metrics.slo().is_some_and(|slo| slo.should_shed(body.priority.unwrap_or_default())) {
+        return Err(ApiError::Shed);
+    }
+
+    let config = llm_configs.get(&body.model);
+    if let Some(config) = config {
+        if body.messages.len() > config.max_messages {
+            return Err(ApiError::TooManyMessages(format!(
+                "Too many messages: {} given, model {} accepts at most {}.",
+                body.messages.len(),
+                body.model,
+                config.max_messages
+            )));
+        }
+    }
+
+    let capabilities = config.map(|c| c.capabilities).unwrap_or_default();
+    let wants_tools = body.tools.as_ref().is_some_and(|tools| !tools.is_empty())
+        || matches!(body.tool_choice, Some(ToolChoice::Function { .. }));
+    if wants_tools && !capabilities.supports_tools {
+        return Err(ApiError::UnsupportedCapability {
+            param: "tools",
+            message: format!("Model {} does not support tools.", body.model),
+        });
+    }
+
+    let timeout = match body.timeout {
+        Some(secs) if secs > 0.0 && secs.is_finite() => Duration::from_secs_f32(secs),
+        Some(_) => {
+            return Err(ApiError::InvalidValue {
+                param: "timeout",
+                message: "timeout must be a positive number of seconds.".to_owned(),
+            });
+        }
+        None => config
+            .and_then(|c| c.timeout_secs)
+            .map_or(server_config.default_timeout, Duration::from_secs),
+    };
+
+    let max_tokens = match body.max_tokens {
+        Some(max) if max > 0 => Some(max as u32),
+        Some(_) => {
+            return Err(ApiError::InvalidValue {
+                param: "max_tokens",
+                message: "max_tokens must be positive.".to_owned(),
+            });
+        }
+        None => config.and_then(|c| c.max_tokens),
+    };
+
+    let hidden_states = body.hidden_states.unwrap_or(false);
+    if hidden_states && body.stream.unwrap_or(false) {
+        return Err(ApiError::InvalidValue {
+            param: "stream",
+            message: "hidden_states cannot be combined with stream.".to_owned(),
+        });
+    }
+
+    let trim_incomplete = body.trim_incomplete.unwrap_or(false);
+    if trim_incomplete && body.stream.unwrap_or(false) {
+        return Err(ApiError::InvalidValue {
+            param: "stream",
+            message: "trim_incomplete cannot be combined with stream.".to_owned(),
         });
+    }
+
+    let bypass_prompt_cache = body.bypass_prompt_cache.unwrap_or(false);
+    if bypass_prompt_cache && body.prompt_cache.is_some() {
+        return Err(ApiError::InvalidValue {
+            param: "bypass_prompt_cache",
+            message: "prompt_cache cannot be combined with bypass_prompt_cache.".to_owned(),
+        });
+    }
+    if let Some(name) = &body.prompt_cache {
+        let checked = match config {
+            Some(config) => config.prompt_cache_file(name).map(|_| ()),
+            None => Err(format!("Model {} does not support prompt caches", body.model)),
+        };
+        if let Err(message) = checked {
+            return Err(ApiError::InvalidValue {
+                param: "prompt_cache",
+                message,
+            });
+        }
+    }
+
+    let replay_interval = body.replay_interval_ms.map(Duration::from_millis);
+    if replay_interval.is_some() && !(server_config.debug_replay && body.stream.unwrap_or(false)) {
+        return Err(ApiError::InvalidValue {
+            param: "replay_interval_ms",
+            message: "replay_interval_ms needs stream and a server started with --debug-replay-stream."
+                .to_owned(),
+        });
+    }
+
+    // rkllm cannot change core usage per run, so the hint picks the instance
+    let llm_pool = match body.npu_cores {
+        Some(wanted) => {
+            let candidates = llm_pool
+                .iter()
+                .filter(|instance| instance.npu_cores.is_some_and(|cores| cores >= wanted))
+                .collect::<Vec<_>>();
+            if candidates.is_empty() {
+                let most = llm_pool.iter().filter_map(|instance| instance.npu_cores).max();
+                return Err(ApiError::InvalidValue {
+                    param: "npu_cores",
+                    message: match most {
+                        Some(most) => format!(
+                            "Model {} has no instance with {} NPU cores, at most {}.",
+                            body.model, wanted, most
+                        ),
+                        None => format!("Model {} does not declare npu_cores.", body.model),
+                    },
+                });
+            }
+            candidates
+        }
+        None => llm_pool.iter().collect(),
+    };
+
+    let stream_guard = if body.stream.unwrap_or(false) {
+        let Some(guard) = metrics.open_stream(server_config.max_streams) else {
+            return Err(ApiError::TooManyStreams);
+        };
+        Some(guard)
+    } else {
+        None
     };
 
+    // Least busy instance, ties broken at random so idle instances share the load
+    let queued = llm_pool
+        .iter()
+        .map(|instance| instance.queued.load(Ordering::Relaxed))
+        .collect::<Vec<_>>();
+    let least_queued = queued.iter().min().copied().unwrap_or_default();
+    let idlest = llm_pool
+        .iter()
+        .zip(&queued)
+        .filter(|(_, &queued)| queued == least_queued)
+        .map(|(instance, _)| *instance)
+        .collect::<Vec<_>>();
     let mut rng = rand::rng();
-    let llm = llm_pool.choose(&mut rng).unwrap();
+    let llm = &idlest.choose(&mut rng).unwrap().recipient;
+    // Cheap check before the expensive templating and generation
+    if !llm.connected() {
+        return Err(dead_instance(&body.model, &restarter));
+    }
 
     let send_future = llm.send(ProcessMessages {
         messages: body.messages.clone(),
+        normalization: body.output_normalization,
+        debug: body.debug.unwrap_or(false),
+        hidden_states,
+        prompt_cache: body.prompt_cache.clone(),
+        think: body.think,
+        bypass_prompt_cache,
+        max_tokens,
+        stream: body.stream.unwrap_or(false),
     });
 
     match actix_web::rt::time::timeout(std::time::Duration::from_secs(5), send_future).await {
-        Ok(Ok(Ok(receiver))) => {
+        Ok(Ok(Ok(generation))) => {
+            println!("Templated prompt for {} in {:?}", body.model, generation.template_time);
+            metrics.record_template(&body.model, generation.template_time);
+            let token_metrics = metrics.clone();
+            let receiver = generation.stream.inspect(move |piece| {
+                if !piece.is_empty() {
+                    token_metrics.count_token();
+                }
+            });
+            let mut prompt_token_ids = generation.prompt_token_ids;
+            let system_fingerprint = generation.system_fingerprint;
+            let hit_max_tokens = generation.hit_max_tokens;
+            // Stops rkllm at its next token, so a request we gave up on frees the instance
+            let cancel = generation.cancel;
+            let timed_out = Arc::new(AtomicBool::new(false));
             if body.stream.unwrap_or(false) {
-                let object = "chat.completion.chunk".to_owned();
                 let mut stream_counter = 0;
-                let sse_stream = receiver.map(move |content| {
+                let stream_offsets = body.stream_offsets.unwrap_or(false);
+                let mut offset = 0;
+                let receiver = match replay_interval {
+                    Some(interval) => {
+                        let Ok(chunks) =
+                            actix_web::rt::time::timeout(timeout, receiver.collect::<Vec<_>>()).await
+                        else {
+                            cancel.store(true, Ordering::Relaxed);
+                            return Err(ApiError::GenerationTimeout(timeout));
+                        };
+                        futures::stream::iter(chunks)
+                            .then(move |chunk| async move {
+                                actix_web::rt::time::sleep(interval).await;
+                                chunk
+                            })
+                            .boxed()
+                    }
+                    None => {
+                        let finished = Arc::new(AtomicBool::new(false));
+                        let deadline = {
+                            let (finished, timed_out) = (finished.clone(), timed_out.clone());
+                            async move {
+                                actix_web::rt::time::sleep(timeout).await;
+                                if !finished.load(Ordering::Relaxed) {
+                                    timed_out.store(true, Ordering::Relaxed);
+                                    cancel.store(true, Ordering::Relaxed);
+                                }
+                            }
+                        };
+                        receiver
+                            .inspect(move |piece| {
+                                if piece.is_empty() {
+                                    finished.store(true, Ordering::Relaxed);
+                                }
+                            })
+                            .take_until(Box::pin(deadline))
+                            .boxed()
+                    }
+                };
+                // A cut off stream or one rkllm ended by closing the channel
+                // still ends with a finish_reason
+                let receiver = close_stream(receiver);
+                let stream_reasoning = body.stream_reasoning.unwrap_or(false);
+                let mut splitter = ReasoningSplitter::new(generation.starts_in_reasoning);
+                let tag = |(reasoning, text): (bool, String)| {
+                    let kind = if reasoning { ChunkKind::Reasoning } else { ChunkKind::Content };
+                    (Some(kind), text)
+                };
+                // `None` marks the end of the stream, so a held back tail is not lost
+                let receiver = receiver.map(Some).chain(futures::stream::once(async { None })).flat_map(
+                    move |piece| {
+                        let parts = match piece {
+                            Some(piece) if !stream_reasoning => vec![(None, piece)],
+                            // The empty piece closes the stream and must stay last
+                            Some(piece) if piece.is_empty() => {
+                                splitter.finish().map(tag).into_iter().chain([(None, piece)]).collect()
+                            }
+                            Some(piece) => splitter.push(&piece).into_iter().map(tag).collect(),
+                            None => splitter.finish().map(tag).into_iter().collect(),
+                        };
+                        futures::stream::iter(parts)
+                    },
+                );
+                let max_chunk_bytes = server_config.max_sse_chunk_bytes;
+                let receiver = receiver.flat_map(move |(kind, content)| {
+                    futures::stream::iter(
+                        split_chunk(content, max_chunk_bytes).into_iter().map(move |part| (kind, part)),
+                    )
+                });
+                let stream_metrics = metrics.clone();
+                let mut finish = StreamFinish::default();
+                let sse_stream = receiver.map(move |(kind, content)| {
+                    // The stream owns the guard, its slot frees once the client is gone
+                    let _ = &stream_guard;
+                    if stream_counter == 0 {
+                        if let Some(slo) = stream_metrics.slo() {
+                            slo.record(received.elapsed());
+                        }
+                    }
+                    let token = (stream_offsets && !content.is_empty()).then(|| {
+                        let token = TokenOffset {
+                            text: content.clone(),
+                            offset,
+                            elapsed_ms: received.elapsed().as_millis() as u64,
+                        };
+                        offset += content.chars().count();
+                        token
+                    });
                     let choices = vec![Choice {
                         index: 0,
-                        finish_reason: if &content == "" {
-                            Some(FinishReason::Stop)
-                        } else {
-                            None
-                        },
+                        kind,
+                        // A stream already sent its 200, so empty output can only be flagged here
+                        finish_reason: finish.piece(
+                            &content,
+                            hit_max_tokens.load(Ordering::Relaxed),
+                            timed_out.load(Ordering::Relaxed),
+                        ),
                         message: Some(Message {
                             role: if stream_counter == 0 {
                                 Some(Role::Assistant)
                             } else {
                                 None
                             },
-                            content: if &content == "" {
+                            content: if content.is_empty() {
                                 None
                             } else {
                                 Some(Content::String(content))
@@ -194,11 +730,10 @@ pub async fn chat_completions(
                         delta: None,
                     }];
                     let chunk = ChatCompletionsResponse {
-                        id: id.clone(),
-                        object: object.clone(),
-                        created,
-                        choices,
-                        usage: None,
+                        prompt_token_ids: prompt_token_ids.take(),
+                        system_fingerprint: system_fingerprint.clone(),
+                        token,
+                        ..ChatCompletionsResponse::chunk(id.clone(), created, choices)
                     };
 
                     stream_counter += 1;
@@ -207,15 +742,57 @@ pub async fn chat_completions(
                     Ok::<web::Bytes, actix_web::Error>(web::Bytes::from(sse_data))
                     // 轉為 Bytes 並包裝在 Result 中
                 });
-                actix_web::HttpResponse::Ok()
+                if server_config.gzip_streams && accepts_gzip(&req) {
+                    return Ok(actix_web::HttpResponse::Ok()
+                        .content_type("text/event-stream")
+                        .insert_header((CONTENT_ENCODING, "gzip"))
+                        .insert_header((VARY, "Accept-Encoding"))
+                        .streaming(gzip_stream(sse_stream)));
+                }
+                Ok(actix_web::HttpResponse::Ok()
                     .content_type("text/event-stream")
-                    .streaming(sse_stream)
+                    .streaming(sse_stream))
             } else {
-                let a = receiver.collect::<Vec<_>>().await;
-                let content = a.join("");
+                let max_output_bytes = config.map_or(usize::MAX, |c| c.max_output_bytes);
+                let Ok((mut content, truncated)) = actix_web::rt::time::timeout(
+                    timeout,
+                    collect_output(receiver, max_output_bytes),
+                )
+                .await
+                else {
+                    cancel.store(true, Ordering::Relaxed);
+                    return Err(ApiError::GenerationTimeout(timeout));
+                };
+                if let Some(slo) = metrics.slo() {
+                    slo.record(received.elapsed());
+                }
+                if truncated {
+                    println!(
+                        "Truncated {} response at max_output_bytes ({})",
+                        body.model, max_output_bytes
+                    );
+                }
+                let length_limited = truncated || hit_max_tokens.load(Ordering::Relaxed);
+                if trim_incomplete && length_limited {
+                    content.truncate(trim_incomplete_sentence(&content).len());
+                }
+                let hidden_states = match generation.hidden_states {
+                    Some(receiver) => receiver.await.ok(),
+                    None => None,
+                };
+                let empty = empty_output(
+                    &content,
+                    hidden_states.is_some(),
+                    config.map(|c| c.empty_output).unwrap_or_default(),
+                )?;
+                if empty {
+                    println!("Model {} produced no output", body.model);
+                }
+                if plain_text {
+                    return Ok(idempotency.respond(idempotency_key, "text/plain; charset=utf-8", content));
+                }
 
                 // TODO: 執行完解包
-                let object = "chat.completion".to_owned();
                 let usage = Usage {
                     // TODO: 要給實際數字
                     completion_tokens: 9,
@@ -225,40 +802,148 @@ pub async fn chat_completions(
                 // TODO
                 let choices = vec![Choice {
                     index: 0,
+                    kind: None,
                     message: Some(Message {
                         role: Some(Role::Assistant),
                         content: Some(Content::String(content)),
                     }),
                     delta: None,
-                    finish_reason: Some(FinishReason::Stop),
+                    finish_reason: Some(finish_reason(!empty, length_limited, false)),
                 }];
 
-                HttpResponse::Ok().json(ChatCompletionsResponse {
-                    id,
-                    object,
-                    created,
-                    choices,
+                let response = ChatCompletionsResponse {
                     usage: Some(usage),
-                })
+                    system_fingerprint,
+                    prompt_token_ids,
+                    hidden_states,
+                    ..ChatCompletionsResponse::completion(id, created, choices)
+                };
+                Ok(idempotency.respond(
+                    idempotency_key,
+                    "application/json",
+                    serde_json::to_vec(&response).unwrap(),
+                ))
             }
         }
-        Ok(Ok(Err(e))) => HttpResponse::InternalServerError().json(OpenAiError {
-            message: format!("Internal processing error: {:?}", e),
-            code: "processing_error".to_owned(),
-            r#type: "internal_error".to_owned(),
-            param: None,
-        }),
-        Err(_timeout) => HttpResponse::UnavailableForLegalReasons().json(OpenAiError {
-            message: format!("Server Busy."),
-            code: "server_".to_owned(),
-            r#type: "internal_error".to_owned(),
-            param: None,
-        }),
-        Ok(Err(e)) => HttpResponse::UnavailableForLegalReasons().json(OpenAiError {
-            message: format!("Internal server error:{}", e),
-            code: "server_".to_owned(),
-            r#type: "internal_error".to_owned(),
-            param: None,
-        }),
+        Ok(Ok(Err(ApiError::DeadInstance))) | Ok(Err(MailboxError::Closed)) => {
+            Err(dead_instance(&body.model, &restarter))
+        }
+        Ok(Ok(Err(e))) => Err(e),
+        Err(_timeout) => Err(ApiError::InstanceBusy),
+        Ok(Err(e)) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, ResponseError};
+
+    use super::*;
+
+    #[test]
+    fn completion_objects() {
+        let completion = ChatCompletionsResponse::completion("id".to_owned(), 0, vec![]);
+        assert_eq!(serde_json::to_value(&completion).unwrap()["object"], "chat.completion");
+        let chunk = ChatCompletionsResponse::chunk("id".to_owned(), 0, vec![]);
+        assert_eq!(serde_json::to_value(&chunk).unwrap()["object"], "chat.completion.chunk");
+    }
+
+    #[test]
+    fn identical_retries_share_a_fingerprint() {
+        let cache = IdempotencyCache::new(Duration::from_secs(60));
+        let req = actix_web::test::TestRequest::default()
+            .insert_header(("Idempotency-Key", "retry-1"))
+            .to_http_request();
+        let body = r#"{
+            "model": "qwen",
+            "messages": [{"role": "user", "content": "hi"}],
+            "metadata": {"user": "u1", "session": "s1", "trace": "t1", "team": "a"},
+            "logit_bias": {"1": 0.5, "2": -1.0, "3": 2.0}
+        }"#;
+        let send = || {
+            let request = serde_json::from_str::<ChatCompletionsRequest>(body).unwrap();
+            cache.key_for(&req, &request, false).unwrap()
+        };
+        let first = send();
+        let Lookup::Miss(reservation) = cache.lookup(first.0.clone(), first.1) else {
+            panic!("the first request is a miss");
+        };
+        cache.respond(Some(reservation), "application/json", "{}");
+        // Fresh `HashMap`s iterate in another order, that is no new request
+        for _ in 0..20 {
+            let (key, fingerprint) = send();
+            assert_eq!(fingerprint, first.1);
+            assert!(matches!(cache.lookup(key, fingerprint), Lookup::Hit(_)));
+        }
+    }
+
+    #[test]
+    fn small_pieces_stay_whole() {
+        assert_eq!(split_chunk("hello".to_owned(), 16), ["hello"]);
+        assert_eq!(split_chunk(String::new(), 16), [""]);
+    }
+
+    #[test]
+    fn large_pieces_split_on_char_boundaries() {
+        // One artificially large token, like a runaway repeat
+        let token = "ab".repeat(50_000);
+        let parts = split_chunk(token.clone(), 16384);
+        assert_eq!(parts.len(), 7);
+        assert!(parts.iter().all(|part| part.len() <= 16384));
+        assert_eq!(parts.concat(), token);
+
+        // Three byte chars never split in the middle
+        let parts = split_chunk("日本語です".to_owned(), 4);
+        assert_eq!(parts, ["日", "本", "語", "で", "す"]);
+        // A char longer than the limit still goes out whole
+        assert_eq!(split_chunk("é日".to_owned(), 1), ["é", "日"]);
+    }
+
+    /// What a model that only emits whitespace sends.
+    fn whitespace_model() -> impl futures::Stream<Item = String> + Unpin {
+        futures::stream::iter([" ", "\n", "\t  "].map(str::to_owned))
+    }
+
+    #[actix_web::test]
+    async fn whitespace_only_completion_is_empty() {
+        let (content, truncated) = collect_output(whitespace_model(), usize::MAX).await;
+        assert!(!truncated);
+        assert!(matches!(empty_output(&content, false, EmptyOutput::Warn), Ok(true)));
+        assert!(matches!(finish_reason(false, false, false), FinishReason::ModelError));
+        let error = empty_output(&content, false, EmptyOutput::Error).unwrap_err();
+        assert_eq!(error.code(), "empty_output");
+        assert_eq!(error.status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+        // Hidden state requests return no text on purpose
+        assert!(matches!(empty_output("", true, EmptyOutput::Error), Ok(false)));
+        assert!(matches!(empty_output(" ok ", false, EmptyOutput::Error), Ok(false)));
+    }
+
+    #[actix_web::test]
+    async fn whitespace_only_stream_ends_with_model_error() {
+        // rkllm ends the run by closing the channel, without an empty piece
+        let pieces = close_stream(whitespace_model()).collect::<Vec<_>>().await;
+        assert_eq!(pieces, [" ", "\n", "\t  ", ""]);
+        let mut finish = StreamFinish::default();
+        let reasons = pieces.iter().map(|piece| finish.piece(piece, false, false)).collect::<Vec<_>>();
+        assert!(reasons[..3].iter().all(Option::is_none));
+        assert!(matches!(reasons[3], Some(FinishReason::ModelError)));
+
+        let text = futures::stream::iter(["Hi", " there", ""].map(str::to_owned));
+        let pieces = close_stream(text).collect::<Vec<_>>().await;
+        // An empty piece from the model is not doubled
+        assert_eq!(pieces, ["Hi", " there", ""]);
+        let mut finish = StreamFinish::default();
+        let reasons = pieces.iter().map(|piece| finish.piece(piece, false, false)).collect::<Vec<_>>();
+        assert!(matches!(reasons[2], Some(FinishReason::Stop)));
+    }
+
+    #[test]
+    fn empty_output_is_a_model_error() {
+        assert!(matches!(finish_reason(true, false, false), FinishReason::Stop));
+        assert!(matches!(finish_reason(true, true, false), FinishReason::Length));
+        assert!(matches!(finish_reason(false, false, false), FinishReason::ModelError));
+        assert!(matches!(finish_reason(false, true, false), FinishReason::ModelError));
+        // A stream cut off by the timeout ends with length, text or not
+        assert!(matches!(finish_reason(false, false, true), FinishReason::Length));
     }
 }
@@ -0,0 +1,117 @@
+use std::io::Write;
+
+use actix_web::{http::header::ACCEPT_ENCODING, web::Bytes, HttpRequest};
+use flate2::{write::GzEncoder, Compression};
+use futures::{Stream, StreamExt};
+
+/// Whether the client lists `gzip` (or `*`) in `Accept-Encoding` without `q=0`.
+pub fn accepts_gzip(req: &HttpRequest) -> bool {
+    let Some(header) = req.headers().get(ACCEPT_ENCODING).and_then(|value| value.to_str().ok()) else {
+        return false;
+    };
+    header.split(',').any(|coding| {
+        let mut parts = coding.split(';').map(str::trim);
+        let name = parts.next().unwrap_or_default();
+        let refused = parts.any(|param| {
+            param
+                .strip_prefix("q=")
+                .is_some_and(|q| q.parse::<f32>().is_ok_and(|q| q == 0.0))
+        });
+        (name.eq_ignore_ascii_case("gzip") || name == "*") && !refused
+    })
+}
+
+/// Gzips an SSE body as one gzip member, flushing after every frame so the
+/// frame reaches the client at once instead of waiting for a full block. The
+/// gzip trailer follows the last frame.
+pub fn gzip_stream<S, E>(stream: S) -> impl Stream<Item = Result<Bytes, E>>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+{
+    let encoder = GzEncoder::new(Vec::new(), Compression::default());
+    // `None` marks the end, when the trailer is written
+    stream
+        .map(Some)
+        .chain(futures::stream::once(async { None }))
+        .scan(encoder, |encoder, frame| {
+            let compressed = match frame {
+                Some(Ok(frame)) => {
+                    // Writing to a Vec cannot fail
+                    let _ = encoder.write_all(&frame);
+                    let _ = encoder.flush();
+                    Ok(Bytes::from(std::mem::take(encoder.get_mut())))
+                }
+                Some(Err(e)) => Err(e),
+                None => {
+                    let finished = std::mem::replace(encoder, GzEncoder::new(Vec::new(), Compression::none()));
+                    Ok(Bytes::from(finished.finish().unwrap_or_default()))
+                }
+            };
+            futures::future::ready(Some(compressed))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use actix_web::test::TestRequest;
+    use flate2::read::GzDecoder;
+
+    use super::*;
+
+    fn accepts(header: &str) -> bool {
+        accepts_gzip(&TestRequest::default().insert_header((ACCEPT_ENCODING, header)).to_http_request())
+    }
+
+    /// Everything the decoder can get out of a possibly unfinished gzip member.
+    fn decode(compressed: &[u8]) -> String {
+        let mut decoder = GzDecoder::new(compressed);
+        let mut decoded = Vec::new();
+        let mut buf = [0; 256];
+        while let Ok(read @ 1..) = decoder.read(&mut buf) {
+            decoded.extend_from_slice(&buf[..read]);
+        }
+        String::from_utf8(decoded).unwrap()
+    }
+
+    #[test]
+    fn every_frame_decodes_once_flushed() {
+        let frames = ["data: {\"n\":1}\n\n", "data: {\"n\":2}\n\n", "data: [DONE]\n\n"];
+        let stream = futures::stream::iter(frames.map(|frame| Ok::<_, ()>(Bytes::from(frame))));
+        let chunks = futures::executor::block_on(gzip_stream(stream).collect::<Vec<_>>());
+        // One chunk per frame and the trailer
+        assert_eq!(chunks.len(), frames.len() + 1);
+        let mut received = Vec::new();
+        for (sent, chunk) in chunks.into_iter().enumerate() {
+            received.extend_from_slice(&chunk.unwrap());
+            let expected = frames[..(sent + 1).min(frames.len())].concat();
+            assert_eq!(decode(&received), expected);
+        }
+        let mut complete = String::new();
+        GzDecoder::new(received.as_slice()).read_to_string(&mut complete).unwrap();
+        assert_eq!(complete, frames.concat());
+    }
+
+    #[test]
+    fn errors_pass_through() {
+        let stream = futures::stream::iter([Ok(Bytes::from("data: 1\n\n")), Err("closed")]);
+        let chunks = futures::executor::block_on(gzip_stream(stream).collect::<Vec<_>>());
+        assert!(chunks[0].is_ok());
+        assert_eq!(chunks[1], Err("closed"));
+    }
+
+    #[test]
+    fn accept_encoding() {
+        assert!(accepts("gzip"));
+        assert!(accepts("deflate, gzip;q=0.5"));
+        assert!(accepts("GZip"));
+        assert!(accepts("*"));
+        assert!(!accepts("gzip;q=0"));
+        assert!(!accepts("gzip; q=0.0, br"));
+        assert!(!accepts("*;q=0"));
+        assert!(!accepts("deflate, br"));
+        assert!(!accepts(""));
+        assert!(!accepts_gzip(&TestRequest::default().to_http_request()));
+    }
+}
@@ -0,0 +1,329 @@
+use std::{sync::OnceLock, time::Duration};
+
+use actix::MailboxError;
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    models::{AvailableModel, ModelNotFound},
+    OpenAiError,
+};
+
+/// Seconds clients are told to wait before retrying an overloaded server.
+const RETRY_AFTER_SECS: u64 = 5;
+
+/// How [`ApiError`]s are logged, set once from the command line.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorLogFormat {
+    /// `error code=... status=... message="..."`
+    #[default]
+    Text,
+    /// One JSON object per line.
+    Json,
+    Off,
+}
+
+static ERROR_LOG_FORMAT: OnceLock<ErrorLogFormat> = OnceLock::new();
+
+/// Only the first call has an effect, later ones are ignored.
+pub fn set_error_log_format(format: ErrorLogFormat) {
+    let _ = ERROR_LOG_FORMAT.set(format);
+}
+
+/// Every failure a handler can answer with. Each variant has a stable
+/// [`code`](ApiError::code), sent as the body's `code` and logged with it.
+#[derive(Debug)]
+pub enum ApiError {
+    /// Unknown model, or one of the wrong type for the endpoint.
+    ModelNotFound {
+        message: String,
+        available_models: Vec<AvailableModel>,
+    },
+    /// The JSON body does not match the request schema.
+    InvalidBody(String),
+    /// A request field has a value the server cannot honour.
+    InvalidValue { param: &'static str, message: String },
+    TooManyMessages(String),
+    /// The model's config does not declare a capability the request needs.
+    UnsupportedCapability { param: &'static str, message: String },
+    IdempotencyKeyReused,
+    /// A request with the same `Idempotency-Key` is still running.
+    IdempotencyKeyInProgress,
+    /// The model is configured but every instance failed to initialize.
+    ModelLoadFailed { model: String, message: String },
+    /// The multipart body was cut off or malformed.
+    InvalidUpload(String),
+    /// The uploaded file is not usable audio.
+    InvalidAudio(String),
+    TooManyStreams,
+    /// No instance accepted the request in time.
+    InstanceBusy,
+    /// A low priority request while the server is over its latency target.
+    Shed,
+    /// The selected instance's actor or rkllm handle is gone.
+    DeadInstance,
+    /// The model actor could not take the message, it is stopped or overloaded.
+    Mailbox(MailboxError),
+    /// The chat template could not be applied to the messages.
+    TokenizerFailed(String),
+    /// The model failed while handling the request.
+    Processing(String),
+    GenerationTimeout(Duration),
+    /// The completion was empty or only whitespace.
+    EmptyOutput,
+}
+
+impl ApiError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::ModelNotFound { .. } => "model_not_found",
+            ApiError::InvalidBody(_) => "invalid_body",
+            ApiError::InvalidValue { .. } => "invalid_value",
+            ApiError::TooManyMessages(_) => "too_many_messages",
+            ApiError::UnsupportedCapability { .. } => "unsupported_capability",
+            ApiError::IdempotencyKeyReused => "idempotency_key_reused",
+            ApiError::IdempotencyKeyInProgress => "idempotency_key_in_progress",
+            ApiError::ModelLoadFailed { .. } => "model_load_failed",
+            ApiError::InvalidUpload(_) => "invalid_upload",
+            ApiError::InvalidAudio(_) => "invalid_audio",
+            ApiError::TooManyStreams => "too_many_streams",
+            ApiError::InstanceBusy => "instance_busy",
+            ApiError::Shed => "slo_shed",
+            ApiError::DeadInstance => "instance_dead",
+            ApiError::Mailbox(_) => "model_unavailable",
+            ApiError::TokenizerFailed(_) => "tokenizer_failed",
+            ApiError::Processing(_) => "processing_error",
+            ApiError::GenerationTimeout(_) => "generation_timeout",
+            ApiError::EmptyOutput => "empty_output",
+        }
+    }
+
+    fn error_type(&self) -> &'static str {
+        match self.status_code().as_u16() {
+            400..=499 => "invalid_request_error",
+            _ => "server_error",
+        }
+    }
+
+    fn param(&self) -> Option<&'static str> {
+        match self {
+            ApiError::ModelNotFound { .. } | ApiError::ModelLoadFailed { .. } => Some("model"),
+            ApiError::InvalidValue { param, .. } | ApiError::UnsupportedCapability { param, .. } => Some(param),
+            ApiError::TooManyMessages(_) => Some("messages"),
+            ApiError::InvalidUpload(_) | ApiError::InvalidAudio(_) => Some("file"),
+            _ => None,
+        }
+    }
+
+    fn log(&self) {
+        let format = ERROR_LOG_FORMAT.get().copied().unwrap_or_default();
+        if let Some(line) = self.log_line(format) {
+            eprintln!("{}", line);
+        }
+    }
+
+    fn log_line(&self, format: ErrorLogFormat) -> Option<String> {
+        match format {
+            ErrorLogFormat::Text => Some(format!(
+                "error code={} status={} message={:?}",
+                self.code(),
+                self.status_code().as_u16(),
+                self.to_string()
+            )),
+            ErrorLogFormat::Json => Some(
+                serde_json::json!({
+                    "level": "error",
+                    "code": self.code(),
+                    "status": self.status_code().as_u16(),
+                    "message": self.to_string(),
+                })
+                .to_string(),
+            ),
+            ErrorLogFormat::Off => None,
+        }
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApiError::ModelNotFound { message, .. }
+            | ApiError::InvalidValue { message, .. }
+            | ApiError::UnsupportedCapability { message, .. } => f.write_str(message),
+            ApiError::TooManyMessages(message) => f.write_str(message),
+            ApiError::IdempotencyKeyReused => {
+                f.write_str("Idempotency-Key was already used with a different request.")
+            }
+            ApiError::IdempotencyKeyInProgress => {
+                f.write_str("A request with this Idempotency-Key is still running, retry once it finished.")
+            }
+            ApiError::InvalidBody(e) => write!(f, "Invalid request body: {}", e),
+            ApiError::ModelLoadFailed { model, message } => {
+                write!(f, "The model {} failed to load: {}", model, message)
+            }
+            ApiError::InvalidUpload(e) => write!(f, "Incomplete or malformed upload: {}", e),
+            ApiError::InvalidAudio(message) => f.write_str(message),
+            ApiError::TooManyStreams => f.write_str("Too many open streams, try again later."),
+            ApiError::InstanceBusy => f.write_str("Server Busy."),
+            ApiError::Shed => {
+                f.write_str("The server is over its response time target, low priority requests are rejected.")
+            }
+            ApiError::DeadInstance => f.write_str("The model instance is not running, try again later."),
+            ApiError::Mailbox(e) => write!(f, "Model unavailable: {}", e),
+            ApiError::TokenizerFailed(e) => write!(f, "Failed to apply the chat template: {}", e),
+            ApiError::Processing(e) => write!(f, "Internal processing error: {}", e),
+            ApiError::GenerationTimeout(timeout) => {
+                write!(f, "Generation did not finish within {:?}.", timeout)
+            }
+            ApiError::EmptyOutput => {
+                f.write_str("The model produced an empty or whitespace-only completion.")
+            }
+        }
+    }
+}
+
+impl From<MailboxError> for ApiError {
+    fn from(e: MailboxError) -> Self {
+        ApiError::Mailbox(e)
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::ModelNotFound { .. } => StatusCode::NOT_FOUND,
+            ApiError::InvalidBody(_)
+            | ApiError::InvalidValue { .. }
+            | ApiError::TooManyMessages(_)
+            | ApiError::UnsupportedCapability { .. }
+            | ApiError::InvalidUpload(_)
+            | ApiError::InvalidAudio(_) => StatusCode::BAD_REQUEST,
+            ApiError::IdempotencyKeyReused => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::IdempotencyKeyInProgress => StatusCode::CONFLICT,
+            ApiError::TooManyStreams
+            | ApiError::InstanceBusy
+            | ApiError::Shed
+            | ApiError::DeadInstance
+            | ApiError::ModelLoadFailed { .. }
+            | ApiError::Mailbox(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::TokenizerFailed(_) | ApiError::Processing(_) | ApiError::EmptyOutput => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+            ApiError::GenerationTimeout(_) => StatusCode::GATEWAY_TIMEOUT,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        self.log();
+        let mut response = HttpResponse::build(self.status_code());
+        if matches!(
+            self,
+            ApiError::InstanceBusy
+                | ApiError::Shed
+                | ApiError::DeadInstance
+                | ApiError::Mailbox(_)
+                | ApiError::IdempotencyKeyInProgress
+        ) {
+            response.insert_header(("Retry-After", RETRY_AFTER_SECS.to_string()));
+        }
+        let error = OpenAiError {
+            message: self.to_string(),
+            code: self.code().to_owned(),
+            r#type: self.error_type().to_owned(),
+            param: self.param().map(str::to_owned),
+        };
+        match self {
+            ApiError::ModelNotFound { available_models, .. } => response.json(ModelNotFound {
+                error,
+                available_models: available_models.clone(),
+            }),
+            _ => response.json(error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_variant_has_a_stable_code_and_status() {
+        let message = || "message".to_owned();
+        let cases = [
+            (
+                ApiError::ModelNotFound {
+                    message: message(),
+                    available_models: Vec::new(),
+                },
+                "model_not_found",
+                StatusCode::NOT_FOUND,
+            ),
+            (ApiError::InvalidBody(message()), "invalid_body", StatusCode::BAD_REQUEST),
+            (
+                ApiError::InvalidValue {
+                    param: "temperature",
+                    message: message(),
+                },
+                "invalid_value",
+                StatusCode::BAD_REQUEST,
+            ),
+            (ApiError::TooManyMessages(message()), "too_many_messages", StatusCode::BAD_REQUEST),
+            (
+                ApiError::UnsupportedCapability {
+                    param: "tools",
+                    message: message(),
+                },
+                "unsupported_capability",
+                StatusCode::BAD_REQUEST,
+            ),
+            (ApiError::IdempotencyKeyReused, "idempotency_key_reused", StatusCode::UNPROCESSABLE_ENTITY),
+            (ApiError::IdempotencyKeyInProgress, "idempotency_key_in_progress", StatusCode::CONFLICT),
+            (
+                ApiError::ModelLoadFailed {
+                    model: "qwen".to_owned(),
+                    message: message(),
+                },
+                "model_load_failed",
+                StatusCode::SERVICE_UNAVAILABLE,
+            ),
+            (ApiError::InvalidUpload(message()), "invalid_upload", StatusCode::BAD_REQUEST),
+            (ApiError::InvalidAudio(message()), "invalid_audio", StatusCode::BAD_REQUEST),
+            (ApiError::TooManyStreams, "too_many_streams", StatusCode::SERVICE_UNAVAILABLE),
+            (ApiError::InstanceBusy, "instance_busy", StatusCode::SERVICE_UNAVAILABLE),
+            (ApiError::Shed, "slo_shed", StatusCode::SERVICE_UNAVAILABLE),
+            (ApiError::DeadInstance, "instance_dead", StatusCode::SERVICE_UNAVAILABLE),
+            (ApiError::Mailbox(MailboxError::Closed), "model_unavailable", StatusCode::SERVICE_UNAVAILABLE),
+            (ApiError::TokenizerFailed(message()), "tokenizer_failed", StatusCode::INTERNAL_SERVER_ERROR),
+            (ApiError::Processing(message()), "processing_error", StatusCode::INTERNAL_SERVER_ERROR),
+            (
+                ApiError::GenerationTimeout(Duration::from_secs(30)),
+                "generation_timeout",
+                StatusCode::GATEWAY_TIMEOUT,
+            ),
+            (ApiError::EmptyOutput, "empty_output", StatusCode::INTERNAL_SERVER_ERROR),
+        ];
+        for (error, code, status) in cases {
+            assert_eq!((error.code(), error.status_code()), (code, status), "{:?}", error);
+        }
+    }
+
+    #[test]
+    fn json_log_lines_are_one_object() {
+        let line = ApiError::InvalidBody("missing field `model`".to_owned())
+            .log_line(ErrorLogFormat::Json)
+            .unwrap();
+        assert!(!line.contains('\n'));
+        let logged: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(
+            logged,
+            serde_json::json!({
+                "level": "error",
+                "code": "invalid_body",
+                "status": 400,
+                "message": "Invalid request body: missing field `model`",
+            })
+        );
+        assert_eq!(ApiError::Shed.log_line(ErrorLogFormat::Off), None);
+    }
+}
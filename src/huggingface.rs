@@ -1,8 +1,9 @@
-use hf_hub::api::sync::Api;
+use hf_hub::{api::sync::Api, Cache};
 use serde::{Deserialize, Serialize};
+use std::ffi::CString;
 use std::fs::{self, File};
 use std::io::Write;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 /// Checks if a model exists and is accessible on Hugging Face
 /// This function should only be called at runtime, not during compilation
@@ -15,27 +16,210 @@ pub fn check_model_exists(model_id: &str) -> bool {
 }
 
 /// Determines the model type based on model_id naming conventions
-/// This is a simplified approach that doesn't require API calls
-pub fn determine_model_type(model_id: &str) -> ModelType {
+/// This is a simplified approach that doesn't require API calls.
+/// `None` when the name gives no hint either way.
+pub fn determine_model_type(model_id: &str) -> Option<ModelType> {
     // Simple heuristic based on model name
     if model_id.to_lowercase().contains("llm") || 
        model_id.to_lowercase().contains("gpt") ||
        model_id.to_lowercase().contains("llama") ||
        model_id.to_lowercase().contains("mistral") {
-        ModelType::LLM
+        Some(ModelType::LLM)
     } else if model_id.to_lowercase().contains("voice") || 
               model_id.to_lowercase().contains("asr") ||
               model_id.to_lowercase().contains("whisper") {
-        ModelType::ASR
+        Some(ModelType::ASR)
     } else {
-        // Default to LLM if we can't determine
-        ModelType::LLM
+        None
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Guesses whether a model reasons in `<think>` blocks, from its chat template
+/// or the tags in its model card. Any download failure counts as "no".
+pub fn detect_reasoning_model(model_id: &str) -> bool {
+    let Ok(api) = Api::new() else {
+        return false;
+    };
+    let repo = api.model(model_id.to_string());
+    let read = |file: &str| repo.get(file).ok().and_then(|path| fs::read_to_string(path).ok());
+
+    // DeepSeek-R1 and Qwen3 style templates open or strip a think block
+    if let Some(tokenizer_config) = read("tokenizer_config.json") {
+        if tokenizer_config.contains("<think>") {
+            return true;
+        }
+    }
+
+    // Only trust the card's YAML front matter, the prose mentions reasoning far too often
+    read("README.md")
+        .and_then(|card| {
+            let front_matter = card.strip_prefix("---")?.split("\n---").next()?.to_lowercase();
+            Some(front_matter.contains("reasoning"))
+        })
+        .unwrap_or(false)
+}
+
+/// Bytes available to unprivileged users on the filesystem holding `path`,
+/// or its nearest existing ancestor.
+fn available_space(path: &Path) -> std::io::Result<u64> {
+    let existing = path.ancestors().find(|p| p.exists()).unwrap_or(Path::new("/"));
+    let c_path = CString::new(existing.as_os_str().as_encoded_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[derive(Deserialize)]
+struct SizedSibling {
+    rfilename: String,
+    size: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct SizedRepoInfo {
+    siblings: Vec<SizedSibling>,
+}
+
+/// Bytes of `files` (every file when `None`) not yet in the local hub cache.
+fn missing_bytes(model_id: &str, files: Option<&[&str]>) -> Result<u64, String> {
+    let api = Api::new().map_err(|e| e.to_string())?;
+    let body = api
+        .model(model_id.to_owned())
+        .info_request()
+        .query("blobs", "true")
+        .call()
+        .map_err(|e| e.to_string())?
+        .into_string()
+        .map_err(|e| e.to_string())?;
+    let info: SizedRepoInfo = serde_json::from_str(&body).map_err(|e| e.to_string())?;
+    let cache = Cache::default().model(model_id.to_owned());
+    Ok(info
+        .siblings
+        .iter()
+        .filter(|file| files.is_none_or(|files| files.contains(&file.rfilename.as_str())))
+        .filter(|file| cache.get(&file.rfilename).is_none())
+        .filter_map(|file| file.size)
+        .sum())
+}
+
+/// Makes sure the files of `model_id` we are about to download fit. When the
+/// hub cache is too small and `fallback_dir` has room, the model's cache
+/// directory becomes a symlink into `fallback_dir`, so every library that
+/// downloads through hf-hub writes there. Size lookups that fail only warn.
+pub fn ensure_cache_space(model_id: &str, files: Option<&[&str]>, fallback_dir: Option<&Path>) -> Result<(), String> {
+    let needed = match missing_bytes(model_id, files) {
+        Ok(0) => return Ok(()),
+        Ok(needed) => needed,
+        Err(e) => {
+            println!("Warning: Could not get the download size of {}, skipping the disk space check: {}", model_id, e);
+            return Ok(());
+        }
+    };
+    let mb = |bytes: u64| bytes / (1024 * 1024);
+    let hub = Cache::default().path().clone();
+    let repo_dir_name = format!("models--{}", model_id.replace('/', "--"));
+    let link = hub.join(&repo_dir_name);
+    // Follows an earlier fallback symlink to the volume it points at
+    let free = available_space(&link).map_err(|e| format!("Failed to check free space in {}: {}", hub.display(), e))?;
+    if needed <= free {
+        println!("Downloading {} MB for {} to {} ({} MB free)", mb(needed), model_id, hub.display(), mb(free));
+        return Ok(());
+    }
+    let too_small = format!(
+        "{} needs {} MB but only {} MB are free in {}",
+        model_id,
+        mb(needed),
+        mb(free),
+        hub.display()
+    );
+    let Some(fallback_dir) = fallback_dir else {
+        return Err(format!("{}, free some space or pass --fallback-cache-dir", too_small));
+    };
+    if link.exists() && !link.is_symlink() {
+        return Err(format!("{} and part of it is already cached there, so it cannot move to {}", too_small, fallback_dir.display()));
+    }
+    let target: PathBuf = fallback_dir.join(&repo_dir_name);
+    let fallback_free = available_space(fallback_dir)
+        .map_err(|e| format!("Failed to check free space in {}: {}", fallback_dir.display(), e))?;
+    if needed > fallback_free {
+        return Err(format!("{}, and {} has only {} MB", too_small, fallback_dir.display(), mb(fallback_free)));
+    }
+    fs::create_dir_all(&target).map_err(|e| format!("Failed to create {}: {}", target.display(), e))?;
+    if !link.is_symlink() {
+        fs::create_dir_all(&hub).map_err(|e| format!("Failed to create {}: {}", hub.display(), e))?;
+        std::os::unix::fs::symlink(&target, &link)
+            .map_err(|e| format!("Failed to link {} to {}: {}", link.display(), target.display(), e))?;
+    }
+    println!("{}, downloading to {} instead ({} MB free)", too_small, target.display(), mb(fallback_free));
+    Ok(())
+}
+
+/// Used when a config sets no `quantization` and the repo has no
+/// `model.rkllm`: most accurate first, w4a16 runs faster but only on some SoCs.
+pub const DEFAULT_QUANTIZATION_ORDER: &[&str] = &[
+    "w8a8", "w8a8_g128", "w8a8_g256", "w8a8_g512", "w4a16", "w4a16_g32", "w4a16_g64", "w4a16_g128",
+];
+
+/// The quantization in an rkllm file name such as
+/// `Qwen2.5-3B_W8A8_G128_RK3588.rkllm`, lowercased: `w8a8_g128`.
+pub fn quantization_of(file_name: &str) -> Option<String> {
+    let stem = file_name.rsplit('/').next()?.strip_suffix(".rkllm")?.to_lowercase();
+    let tokens = stem.split(['-', '_', '.']).collect::<Vec<_>>();
+    let is_quant = |token: &str| {
+        let Some(rest) = token.strip_prefix('w') else {
+            return false;
+        };
+        rest.split_once('a').is_some_and(|(weights, activations)| {
+            [weights, activations].iter().all(|bits| !bits.is_empty() && bits.chars().all(|c| c.is_ascii_digit()))
+        })
+    };
+    let is_group = |token: &str| {
+        token.strip_prefix('g').is_some_and(|size| !size.is_empty() && size.chars().all(|c| c.is_ascii_digit()))
+    };
+    let at = tokens.iter().position(|token| is_quant(token))?;
+    Some(match tokens.get(at + 1) {
+        Some(group) if is_group(group) => format!("{}_{}", tokens[at], group),
+        _ => tokens[at].to_owned(),
+    })
+}
+
+/// Picks the `.rkllm` file for the first quantization in `preference` that
+/// `files` has, matching names case-insensitively.
+pub fn pick_rkllm_file(model_id: &str, files: &[String], preference: &[&str]) -> Result<String, String> {
+    let mut rkllm = files.iter().filter(|file| file.ends_with(".rkllm")).collect::<Vec<_>>();
+    rkllm.sort();
+    for wanted in preference {
+        let wanted = wanted.to_lowercase();
+        let mut matching = rkllm.iter().filter(|file| quantization_of(file).as_deref() == Some(wanted.as_str()));
+        if let Some(file) = matching.next() {
+            if let Some(other) = matching.next() {
+                println!("Warning: {} has several {} files, using {} rather than {}", model_id, wanted, file, other);
+            }
+            return Ok((*file).clone());
+        }
+    }
+    let available = rkllm
+        .iter()
+        .map(|file| match quantization_of(file) {
+            Some(quant) => format!("{} ({})", file, quant),
+            None => file.to_string(),
+        })
+        .collect::<Vec<_>>();
+    Err(format!(
+        "{} has no .rkllm file quantized as {}, it has: {}",
+        model_id,
+        preference.join(" or "),
+        if available.is_empty() { "none".to_owned() } else { available.join(", ") }
+    ))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ModelType {
+    #[serde(rename = "llm")]
     LLM,
+    #[serde(rename = "asr")]
     ASR,
 }
 
@@ -69,11 +253,18 @@ pub fn create_config_file(model_id: &str, model_type: ModelType) -> std::io::Res
     let file_name = format!("{}.json", name.to_lowercase().replace('-', "_"));
     let config_path = config_dir.join(&file_name);
     
-    // Create config content
+    // Create config content, reasoning models think by default
+    let think = if model_type == ModelType::LLM {
+        let reasoning = detect_reasoning_model(model_id);
+        println!("Detected {} as {}reasoning model", model_id, if reasoning { "" } else { "non-" });
+        Some(reasoning)
+    } else {
+        None
+    };
     let config = ModelConfig {
         modle_path: model_id.to_string(),
         modle_name: name.to_string(),
-        think: if model_type == ModelType::LLM { Some(false) } else { None },
+        think,
     };
     
     // Write config to file
@@ -0,0 +1,223 @@
+use std::{
+    collections::HashMap,
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use actix_web::{web::Bytes, HttpRequest, HttpResponse};
+use serde::Serialize;
+
+/// Request header carrying the client chosen key.
+pub const IDEMPOTENCY_KEY: &str = "Idempotency-Key";
+/// Response header set to `true` when a cached response is replayed.
+pub const IDEMPOTENT_REPLAYED: &str = "Idempotent-Replayed";
+
+#[derive(Debug)]
+struct CachedResponse {
+    content_type: &'static str,
+    body: Bytes,
+    expires: Instant,
+}
+
+#[derive(Debug)]
+struct Entry {
+    fingerprint: u64,
+    /// `None` while the first request with the key is still generating.
+    response: Option<CachedResponse>,
+}
+
+pub enum Lookup<'a> {
+    /// The key is now reserved for this request, see [`IdempotencyCache::respond`].
+    Miss(Reservation<'a>),
+    Hit(HttpResponse),
+    /// The key was already used with a different request body.
+    Mismatch,
+    /// An earlier request with the key has not finished yet.
+    InProgress,
+}
+
+/// Finished non-streaming responses by `Idempotency-Key`, shared by every worker.
+#[derive(Debug)]
+pub struct IdempotencyCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+/// A key held by the request generating its response. Dropped without a
+/// response, e.g. on an error or a client that went away, the key is freed.
+pub struct Reservation<'a> {
+    cache: &'a IdempotencyCache,
+    key: String,
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        let mut entries = self.cache.entries.lock().unwrap();
+        if entries.get(&self.key).is_some_and(|entry| entry.response.is_none()) {
+            entries.remove(&self.key);
+        }
+    }
+}
+
+/// Hashes `value` with object keys in sorted order, so maps deserialized
+/// into `HashMap`s fingerprint the same whatever order they iterate in.
+fn hash_canonical(value: &serde_json::Value, hasher: &mut impl Hasher) {
+    match value {
+        serde_json::Value::Object(fields) => {
+            let mut fields = fields.iter().collect::<Vec<_>>();
+            fields.sort_by_key(|(name, _)| *name);
+            hasher.write_u8(b'{');
+            for (name, value) in fields {
+                name.hash(hasher);
+                hash_canonical(value, hasher);
+            }
+            hasher.write_u8(b'}');
+        }
+        serde_json::Value::Array(items) => {
+            hasher.write_u8(b'[');
+            for item in items {
+                hash_canonical(item, hasher);
+            }
+            hasher.write_u8(b']');
+        }
+        other => other.to_string().hash(hasher),
+    }
+}
+
+impl IdempotencyCache {
+    /// A zero `ttl` disables the cache.
+    pub fn new(ttl: Duration) -> Self {
+        IdempotencyCache {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The key and request fingerprint to cache under, if the client sent a key.
+    pub fn key_for(&self, req: &HttpRequest, body: &impl Serialize, variant: bool) -> Option<(String, u64)> {
+        if self.ttl.is_zero() {
+            return None;
+        }
+        let key = req.headers().get(IDEMPOTENCY_KEY)?.to_str().ok()?;
+        let mut hasher = DefaultHasher::new();
+        hash_canonical(&serde_json::to_value(body).ok()?, &mut hasher);
+        variant.hash(&mut hasher);
+        Some((key.to_owned(), hasher.finish()))
+    }
+
+    pub fn lookup(&self, key: String, fingerprint: u64) -> Lookup<'_> {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, entry| entry.response.as_ref().is_none_or(|cached| cached.expires > now));
+        match entries.get(&key) {
+            Some(entry) if entry.fingerprint != fingerprint => Lookup::Mismatch,
+            Some(Entry {
+                response: Some(cached), ..
+            }) => Lookup::Hit(
+                HttpResponse::Ok()
+                    .content_type(cached.content_type)
+                    .insert_header((IDEMPOTENT_REPLAYED, "true"))
+                    .body(cached.body.clone()),
+            ),
+            Some(_) => Lookup::InProgress,
+            None => {
+                entries.insert(
+                    key.clone(),
+                    Entry {
+                        fingerprint,
+                        response: None,
+                    },
+                );
+                Lookup::Miss(Reservation { cache: self, key })
+            }
+        }
+    }
+
+    /// Builds a 200 response, remembering it first when `reservation` is set.
+    pub fn respond(
+        &self,
+        reservation: Option<Reservation<'_>>,
+        content_type: &'static str,
+        body: impl Into<Bytes>,
+    ) -> HttpResponse {
+        let body = body.into();
+        if let Some(reservation) = reservation {
+            let mut entries = self.entries.lock().unwrap();
+            if let Some(entry) = entries.get_mut(&reservation.key) {
+                entry.response = Some(CachedResponse {
+                    content_type,
+                    body: body.clone(),
+                    expires: Instant::now() + self.ttl,
+                });
+            }
+        }
+        HttpResponse::Ok().content_type(content_type).body(body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    fn cache() -> IdempotencyCache {
+        IdempotencyCache::new(Duration::from_secs(60))
+    }
+
+    fn keyed() -> HttpRequest {
+        TestRequest::default().insert_header((IDEMPOTENCY_KEY, "retry-1")).to_http_request()
+    }
+
+    #[test]
+    fn fingerprints_ignore_map_order() {
+        let cache = cache();
+        // Every deserialization gets its own `RandomState`, so both orders occur
+        let fingerprints = (0..20)
+            .map(|_| {
+                let metadata = serde_json::from_str::<HashMap<String, String>>(
+                    r#"{"user": "u1", "session": "s1", "trace": "t1", "team": "a"}"#,
+                )
+                .unwrap();
+                cache.key_for(&keyed(), &metadata, false).unwrap().1
+            })
+            .collect::<Vec<_>>();
+        assert!(fingerprints.iter().all(|&fingerprint| fingerprint == fingerprints[0]));
+
+        let other = HashMap::from([("user", "u2")]);
+        assert_ne!(cache.key_for(&keyed(), &other, false).unwrap().1, fingerprints[0]);
+        assert_ne!(cache.key_for(&keyed(), &other, true).unwrap().1, cache.key_for(&keyed(), &other, false).unwrap().1);
+        assert!(cache.key_for(&TestRequest::default().to_http_request(), &other, false).is_none());
+        assert!(IdempotencyCache::new(Duration::ZERO).key_for(&keyed(), &other, false).is_none());
+    }
+
+    #[test]
+    fn replays_finished_responses() {
+        let cache = cache();
+        let Lookup::Miss(reservation) = cache.lookup("k".to_owned(), 1) else {
+            panic!("expected a miss");
+        };
+        cache.respond(Some(reservation), "application/json", "{}");
+        let Lookup::Hit(replayed) = cache.lookup("k".to_owned(), 1) else {
+            panic!("expected a hit");
+        };
+        assert_eq!(replayed.headers().get(IDEMPOTENT_REPLAYED).unwrap(), "true");
+        assert!(matches!(cache.lookup("k".to_owned(), 2), Lookup::Mismatch));
+    }
+
+    #[test]
+    fn retries_during_generation_do_not_generate_again() {
+        let cache = cache();
+        let Lookup::Miss(reservation) = cache.lookup("k".to_owned(), 1) else {
+            panic!("expected a miss");
+        };
+        assert!(matches!(cache.lookup("k".to_owned(), 1), Lookup::InProgress));
+        assert!(matches!(cache.lookup("k".to_owned(), 2), Lookup::Mismatch));
+        // A failed request frees its key for the retry
+        drop(reservation);
+        assert!(matches!(cache.lookup("k".to_owned(), 1), Lookup::Miss(_)));
+    }
+}
@@ -1,8 +1,21 @@
 pub mod audio;
 pub mod chat;
+pub mod compress;
+pub mod error;
 pub mod huggingface;
+pub mod idempotency;
 pub mod llm;
+pub mod metrics;
 pub mod asr;
+pub mod models;
+pub mod normalize;
+pub mod npu;
+#[cfg(feature = "otlp")]
+pub mod otlp;
+pub mod response;
+pub mod shutdown;
+pub mod slo;
+pub mod watchdog;
 
 use std::{io::Read, pin::Pin};
 
@@ -11,7 +24,26 @@ pub use rkllm_rs::prelude::RkllmCallbackHandler;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Server wide settings from the command line, shared with every handler.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// Generation timeout when neither the request nor the model config sets one.
+    pub default_timeout: std::time::Duration,
+    /// Path the OpenAI routes are mounted under, `/v1` or empty for the root.
+    pub api_prefix: String,
+    /// Test aid: lets requests ask for a generated response replayed as a stream.
+    pub debug_replay: bool,
+    /// Streaming responses allowed open at once, `None` for no limit.
+    pub max_streams: Option<usize>,
+    /// Largest content piece sent in one SSE frame, longer pieces are split.
+    pub max_sse_chunk_bytes: usize,
+    /// Request body field renames for non-standard clients, alias to real name.
+    pub field_aliases: std::collections::HashMap<String, String>,
+    /// Gzip streaming responses for clients that accept it.
+    pub gzip_streams: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, utoipa::ToSchema)]
 pub struct OpenAiError {
     pub message: String,
     pub r#type: String,
@@ -55,10 +87,79 @@ pub struct Message {
     pub content: Option<Content>,
 }
 
+/// Last hidden layer of the prompt: `data` holds `num_tokens` rows of `embd_size` floats.
+#[derive(Debug, Clone, Deserialize, Serialize, utoipa::ToSchema)]
+pub struct HiddenStates {
+    pub embd_size: usize,
+    pub num_tokens: usize,
+    pub data: Vec<Vec<f32>>,
+}
+
+pub struct Generation {
+    pub stream: Pin<Box<dyn futures::Stream<Item = String> + Send + 'static>>,
+    /// Token ids of the templated prompt, only filled for debug requests.
+    pub prompt_token_ids: Option<Vec<u32>>,
+    /// Resolves once rkllm reports the hidden layer, only for `hidden_states` requests.
+    pub hidden_states: Option<tokio::sync::oneshot::Receiver<HiddenStates>>,
+    /// Identifies the model file and generation config that produced this.
+    pub system_fingerprint: Option<String>,
+    /// CPU time spent applying the chat template and tokenizing the prompt.
+    pub template_time: std::time::Duration,
+    /// The templated prompt ends inside an open `<think>` block.
+    pub starts_in_reasoning: bool,
+    /// Set once generation was cut off at `max_tokens`.
+    pub hit_max_tokens: std::sync::Arc<std::sync::atomic::AtomicBool>,
+    /// Setting it aborts the run at its next token, e.g. once the request timed out.
+    pub cancel: std::sync::Arc<std::sync::atomic::AtomicBool>,
+}
+
+/// Asks main to reload every model, a no-op unless `--restart-dead-instances` is set.
+#[derive(Debug, Clone, Default)]
+pub struct Restarter(Option<tokio::sync::mpsc::Sender<String>>);
+
+impl Restarter {
+    pub fn new(reloads: tokio::sync::mpsc::Sender<String>) -> Self {
+        Restarter(Some(reloads))
+    }
+
+    pub fn request(&self, reason: String) {
+        if let Some(reloads) = &self.0 {
+            // A full channel already has a reload pending
+            let _ = reloads.try_send(reason);
+        }
+    }
+}
+
+/// One loaded LLM instance as seen by the chat handler.
+#[derive(Clone)]
+pub struct LlmInstance {
+    pub recipient: actix::Recipient<ProcessMessages>,
+    /// NPU cores the instance's model file runs on, from its config.
+    pub npu_cores: Option<u32>,
+    /// Generations queued or running on the instance.
+    pub queued: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+}
+
 #[derive(actix::Message)]
-#[rtype(result = "Result<Pin<Box<dyn futures::Stream<Item = String> + Send + 'static>>, ()>")]
+#[rtype(result = "Result<Generation, error::ApiError>")]
 pub struct ProcessMessages {
     pub messages: Vec<Message>,
+    /// Overrides the model's configured `output_normalization` when set.
+    pub normalization: Option<normalize::OutputNormalization>,
+    /// Also tokenize the final prompt and return its ids.
+    pub debug: bool,
+    /// Run in `InferGetLastHiddenLayer` mode instead of generating text.
+    pub hidden_states: bool,
+    /// Named prompt cache to load, or to save when it does not exist yet.
+    pub prompt_cache: Option<String>,
+    /// Overrides the model's `think` and `auto_think` settings.
+    pub think: Option<bool>,
+    /// Run without any prompt cache, neither loading nor saving one.
+    pub bypass_prompt_cache: bool,
+    /// Stop generating after this many tokens.
+    pub max_tokens: Option<u32>,
+    /// The response is streamed, which decides its queue under `queue_fairness`.
+    pub stream: bool,
 }
 
 #[derive(actix::Message)]
@@ -76,5 +177,11 @@ pub enum AsrText {
 #[rtype(result = "Result<(), ()>")]
 pub struct ShutdownMessages;
 
-pub trait ASR: Actor + Handler<ProcessAudio> + Handler<ShutdownMessages> + AIModel {}
-pub trait LLM: Actor + Handler<ProcessMessages> + Handler<ShutdownMessages> + AIModel {}
+/// Liveness probe, answered `false` once the model handle is destroyed. An
+/// actor that does not answer at all is stuck.
+#[derive(actix::Message)]
+#[rtype(result = "bool")]
+pub struct Ping;
+
+pub trait ASR: Actor + Handler<ProcessAudio> + Handler<ShutdownMessages> + Handler<Ping> + AIModel {}
+pub trait LLM: Actor + Handler<ProcessMessages> + Handler<ShutdownMessages> + Handler<Ping> + AIModel {}
@@ -1 +1,4 @@
-pub mod simple;
\ No newline at end of file
+pub mod queue;
+pub mod repetition;
+pub mod simple;
+pub mod think;
@@ -0,0 +1,213 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    time::Instant,
+};
+
+use serde::Deserialize;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Which waiting generation a busy instance runs next.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QueueFairness {
+    /// In arrival order.
+    #[default]
+    Fifo,
+    /// Alternate between streaming and non-streaming requests while both
+    /// wait, so neither kind waits behind more than one of the other.
+    Interleave,
+    /// Non-streaming requests first. Streams only run when none wait.
+    NonStreamingFirst,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum JobKind {
+    Streaming,
+    NonStreaming,
+}
+
+impl JobKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            JobKind::Streaming => "streaming",
+            JobKind::NonStreaming => "non_streaming",
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct WaitStats {
+    pub jobs: u64,
+    pub seconds: f64,
+}
+
+/// Time generations spent queued before their instance started them, by
+/// model name and kind, read by `/metrics`.
+static WAITS: Mutex<Option<HashMap<(String, JobKind), WaitStats>>> = Mutex::new(None);
+
+/// Every model's wait totals, sorted by model and kind.
+pub fn wait_stats() -> Vec<(String, JobKind, WaitStats)> {
+    let waits = WAITS.lock().unwrap();
+    let mut stats = waits
+        .iter()
+        .flatten()
+        .map(|((model, kind), stats)| (model.clone(), *kind, *stats))
+        .collect::<Vec<_>>();
+    stats.sort_by(|a, b| (&a.0, a.1.as_str()).cmp(&(&b.0, b.1.as_str())));
+    stats
+}
+
+struct Pending {
+    job: Job,
+    enqueued: Instant,
+}
+
+#[derive(Default)]
+struct Queues {
+    streaming: VecDeque<Pending>,
+    non_streaming: VecDeque<Pending>,
+    last: Option<JobKind>,
+    closed: bool,
+}
+
+impl Queues {
+    fn next(&mut self, fairness: QueueFairness) -> Option<(JobKind, Pending)> {
+        let kind = match (self.streaming.front(), self.non_streaming.front()) {
+            (None, None) => return None,
+            (Some(_), None) => JobKind::Streaming,
+            (None, Some(_)) => JobKind::NonStreaming,
+            (Some(streaming), Some(non_streaming)) => match fairness {
+                QueueFairness::Fifo if streaming.enqueued <= non_streaming.enqueued => JobKind::Streaming,
+                QueueFairness::Fifo => JobKind::NonStreaming,
+                QueueFairness::Interleave if self.last == Some(JobKind::Streaming) => JobKind::NonStreaming,
+                QueueFairness::Interleave => JobKind::Streaming,
+                QueueFairness::NonStreamingFirst => JobKind::NonStreaming,
+            },
+        };
+        self.last = Some(kind);
+        let queue = match kind {
+            JobKind::Streaming => &mut self.streaming,
+            JobKind::NonStreaming => &mut self.non_streaming,
+        };
+        queue.pop_front().map(|pending| (kind, pending))
+    }
+}
+
+struct Shared {
+    queues: Mutex<Queues>,
+    ready: Condvar,
+    alive: AtomicBool,
+}
+
+/// Clears `alive` when the worker thread ends, also by a panicking job.
+struct AliveGuard(Arc<Shared>);
+
+impl Drop for AliveGuard {
+    fn drop(&mut self) {
+        self.0.alive.store(false, Ordering::Relaxed);
+        // Dropping what is still queued ends those requests' streams
+        if let Ok(mut queues) = self.0.queues.lock() {
+            queues.streaming.clear();
+            queues.non_streaming.clear();
+        }
+    }
+}
+
+/// The thread every blocking `run` or inference on one handle happens on.
+/// Generations and transcriptions used to run on the actix system thread,
+/// which let one instance's job hold up every other instance and actor.
+pub struct Worker {
+    shared: Arc<Shared>,
+    /// Queued and running generations, the chat handler prefers idle instances.
+    queued: Arc<AtomicUsize>,
+}
+
+impl std::fmt::Debug for Worker {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Worker").field("queued", &self.queued).finish_non_exhaustive()
+    }
+}
+
+impl Worker {
+    pub fn spawn(model: &str, fairness: QueueFairness) -> std::io::Result<Self> {
+        let shared = Arc::new(Shared {
+            queues: Mutex::new(Queues::default()),
+            ready: Condvar::new(),
+            alive: AtomicBool::new(true),
+        });
+        let guard = AliveGuard(shared.clone());
+        let model = model.to_owned();
+        std::thread::Builder::new().name(format!("npu-{}", model)).spawn(move || {
+            let shared = guard.0.clone();
+            loop {
+                let mut queues = shared.queues.lock().unwrap();
+                let (kind, pending) = loop {
+                    if let Some(next) = queues.next(fairness) {
+                        break next;
+                    }
+                    // Pending jobs still run after close, they see the destroyed handle
+                    if queues.closed {
+                        return;
+                    }
+                    queues = shared.ready.wait(queues).unwrap();
+                };
+                drop(queues);
+                let waited = pending.enqueued.elapsed().as_secs_f64();
+                let mut waits = WAITS.lock().unwrap();
+                let stats = waits.get_or_insert_with(HashMap::new).entry((model.clone(), kind)).or_default();
+                stats.jobs += 1;
+                stats.seconds += waited;
+                drop(waits);
+                (pending.job)();
+            }
+        })?;
+        Ok(Worker {
+            shared,
+            queued: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    pub fn queued(&self) -> Arc<AtomicUsize> {
+        self.queued.clone()
+    }
+
+    /// False when the thread is gone, e.g. after a generation panicked.
+    pub fn dispatch(&self, kind: JobKind, job: impl FnOnce() + Send + 'static) -> bool {
+        // Checked under the lock the dying thread clears the queues with
+        let mut queues = self.shared.queues.lock().unwrap();
+        if !self.alive() {
+            return false;
+        }
+        self.queued.fetch_add(1, Ordering::Relaxed);
+        let queued = self.queued.clone();
+        let pending = Pending {
+            job: Box::new(move || {
+                job();
+                queued.fetch_sub(1, Ordering::Relaxed);
+            }),
+            enqueued: Instant::now(),
+        };
+        match kind {
+            JobKind::Streaming => queues.streaming.push_back(pending),
+            JobKind::NonStreaming => queues.non_streaming.push_back(pending),
+        }
+        self.shared.ready.notify_one();
+        true
+    }
+
+    pub fn alive(&self) -> bool {
+        self.shared.alive.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        self.shared.queues.lock().unwrap().closed = true;
+        self.shared.ready.notify_all();
+    }
+}
@@ -0,0 +1,175 @@
+use std::collections::VecDeque;
+
+use serde::Deserialize;
+
+/// Stops generation once the model repeats itself, which quantized models
+/// sometimes do until they run out of tokens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct LoopDetection {
+    /// Longest repeating run of tokens looked for, shorter ones are found too.
+    #[serde(default = "default_ngram_size")]
+    pub ngram_size: usize,
+    /// Back-to-back occurrences of the run that count as a loop.
+    #[serde(default = "default_repeats")]
+    pub repeats: usize,
+    /// Shortest run that counts. Runs of one token over and over, such as
+    /// indentation or a `-` rule, only count when this is 1.
+    #[serde(default = "default_min_ngram_size")]
+    pub min_ngram_size: usize,
+}
+
+fn default_ngram_size() -> usize {
+    8
+}
+
+fn default_min_ngram_size() -> usize {
+    2
+}
+
+fn default_repeats() -> usize {
+    10
+}
+
+impl LoopDetection {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.ngram_size == 0 {
+            return Err("loop_detection.ngram_size must be positive".to_owned());
+        }
+        if self.repeats < 2 {
+            return Err("loop_detection.repeats must be at least 2".to_owned());
+        }
+        if self.min_ngram_size == 0 || self.min_ngram_size > self.ngram_size {
+            return Err("loop_detection.min_ngram_size must be between 1 and ngram_size".to_owned());
+        }
+        Ok(())
+    }
+}
+
+/// Watches the generated tokens of one run for a repeating n-gram.
+#[derive(Debug)]
+pub struct LoopDetector {
+    settings: LoopDetection,
+    recent: VecDeque<String>,
+}
+
+impl LoopDetector {
+    pub fn new(settings: LoopDetection) -> Self {
+        LoopDetector {
+            settings,
+            recent: VecDeque::with_capacity(settings.ngram_size * settings.repeats),
+        }
+    }
+
+    /// Adds the next token's text and returns the length of the n-gram that
+    /// now ends the output `repeats` times in a row, if any.
+    pub fn push(&mut self, token: &str) -> Option<usize> {
+        if self.recent.len() == self.settings.ngram_size * self.settings.repeats {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(token.to_owned());
+        let LoopDetection {
+            ngram_size,
+            repeats,
+            min_ngram_size,
+        } = self.settings;
+        (min_ngram_size..=ngram_size).find(|&n| {
+            let window = n * repeats;
+            // An n-gram made of a shorter unit below the minimum does not count
+            self.has_period(n, window) && !(1..min_ngram_size).any(|unit| self.has_period(unit, window))
+        })
+    }
+
+    /// Whether the last `window` tokens repeat every `period` tokens.
+    fn has_period(&self, period: usize, window: usize) -> bool {
+        let Some(start) = self.recent.len().checked_sub(window) else {
+            return false;
+        };
+        (start + period..self.recent.len()).all(|i| self.recent[i] == self.recent[i - period])
+    }
+
+    /// The text of the last `n` tokens, for the log line.
+    pub fn tail(&self, n: usize) -> String {
+        self.recent.iter().skip(self.recent.len().saturating_sub(n)).map(String::as_str).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detector(ngram_size: usize, repeats: usize) -> LoopDetector {
+        LoopDetector::new(LoopDetection {
+            ngram_size,
+            repeats,
+            min_ngram_size: 2,
+        })
+    }
+
+    /// Pushes every token and returns the first detection with its position.
+    fn detect(detector: &mut LoopDetector, tokens: &[&str]) -> Option<(usize, usize)> {
+        tokens
+            .iter()
+            .enumerate()
+            .find_map(|(at, token)| detector.push(token).map(|n| (at, n)))
+    }
+
+    #[test]
+    fn repeated_ngram_triggers() {
+        let tokens = ["I", " am", " sorry", "."].repeat(10);
+        // Fires on the last token of the tenth repetition
+        assert_eq!(detect(&mut detector(8, 10), &tokens), Some((39, 4)));
+    }
+
+    #[test]
+    fn one_repetition_short_does_not_trigger() {
+        let tokens = ["a", "b", "c"].repeat(9);
+        assert_eq!(detect(&mut detector(8, 10), &tokens), None);
+    }
+
+    #[test]
+    fn prose_does_not_trigger() {
+        let prose = "The quick brown fox jumps over the lazy dog and then the dog sleeps while the fox runs away into the woods"
+            .split_inclusive(' ')
+            .collect::<Vec<_>>();
+        assert_eq!(detect(&mut detector(8, 2), &prose), None);
+    }
+
+    #[test]
+    fn detects_after_the_buffer_wrapped() {
+        let mut detector = detector(4, 3);
+        // Far more tokens than the 12 the buffer holds
+        let prose = (0..100).map(|i| i.to_string()).collect::<Vec<_>>();
+        let prose = prose.iter().map(String::as_str).collect::<Vec<_>>();
+        assert_eq!(detect(&mut detector, &prose), None);
+        assert_eq!(detect(&mut detector, &["x", "y", "x", "y", "x", "y"]), Some((5, 2)));
+        assert_eq!(detector.tail(2), "xy");
+    }
+
+    #[test]
+    fn single_token_runs_need_min_ngram_size_one() {
+        let indentation = [" "; 40];
+        assert_eq!(detect(&mut detector(8, 10), &indentation), None);
+        let mut detector = LoopDetector::new(LoopDetection {
+            ngram_size: 8,
+            repeats: 10,
+            min_ngram_size: 1,
+        });
+        assert_eq!(detect(&mut detector, &indentation), Some((9, 1)));
+    }
+
+    #[test]
+    fn validates_sizes() {
+        let settings = |ngram_size, repeats, min_ngram_size| LoopDetection {
+            ngram_size,
+            repeats,
+            min_ngram_size,
+        };
+        assert!(settings(8, 10, 2).validate().is_ok());
+        assert!(settings(0, 10, 1).validate().is_err());
+        assert!(settings(8, 1, 2).validate().is_err());
+        assert!(settings(8, 10, 0).validate().is_err());
+        assert!(settings(2, 10, 3).validate().is_err());
+        let defaults = serde_json::from_str::<LoopDetection>("{}").unwrap();
+        assert_eq!(defaults, settings(8, 10, 2));
+    }
+}
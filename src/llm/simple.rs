@@ -1,40 +1,421 @@
 use actix::Actor;
-use hf_hub::api::sync::Api;
+use hf_hub::api::sync::{Api, ApiRepo};
 use rkllm_rs::prelude::*;
 use serde::Deserialize;
 use serde_variant::to_variant_name;
 use std::ffi::CString;
-use std::pin::Pin;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Once};
+use std::time::{Duration, Instant};
+use tokenizers::Tokenizer;
+use tokio::sync::mpsc::error::TrySendError;
 use tokio_stream::wrappers::ReceiverStream;
 
 use autotokenizer::AutoTokenizer;
 use autotokenizer::DefaultPromptMessage;
 
+use crate::error::ApiError;
+use crate::huggingface::{pick_rkllm_file, ModelType, DEFAULT_QUANTIZATION_ORDER};
+use crate::llm::queue::{JobKind, QueueFairness, Worker};
+use crate::llm::repetition::{LoopDetection, LoopDetector};
+use crate::llm::think::looks_complex;
+use crate::models::{model_file_id, system_fingerprint, Capabilities, ModelDetails, SamplingDefaults};
+use crate::normalize::OutputNormalization;
+use crate::shutdown::HandleGuard;
 use crate::AIModel;
+use crate::Generation;
+use crate::HiddenStates;
 use crate::ProcessMessages;
+use crate::Ping;
 use crate::ShutdownMessages;
 use crate::LLM;
 
+/// What a completion whose text is empty or only whitespace turns into.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EmptyOutput {
+    /// Keep the 200 but set `finish_reason` to `ModelError`.
+    #[default]
+    Warn,
+    /// Answer non-streaming requests with a 500 `empty_output`.
+    Error,
+}
+
 #[derive(Debug, Clone, Default, Deserialize)]
 pub struct SimpleLLMConfig {
     pub modle_path: String,
     pub modle_name: String,
     pub cache_path: Option<String>,
     pub think: bool,
+    /// Tokenizer `legacy` value used when `tokenizer_config.json` has none.
     #[serde(default = "default_legacy")]
     pub legacy: bool,
+    /// Forces `legacy` into the tokenizer config even when it sets its own.
+    #[serde(default)]
+    pub force_legacy: bool,
+    /// Default output transform, requests may override it.
+    pub output_normalization: Option<OutputNormalization>,
+    /// Chat requests with more messages are rejected before templating.
+    #[serde(default = "default_max_messages")]
+    pub max_messages: usize,
+    /// Non-streaming responses are truncated once this much text is buffered.
+    #[serde(default = "default_max_output_bytes")]
+    pub max_output_bytes: usize,
+    /// Generation timeout for this model, overrides the server default.
+    pub timeout_secs: Option<u64>,
+    /// Requests needing a capability the model lacks are rejected.
+    #[serde(default)]
+    pub capabilities: Capabilities,
+    /// Where named prompt caches picked by requests live, none disables them.
+    pub prompt_cache_dir: Option<String>,
+    /// Experimental: decide `think` per request with [`looks_complex`].
+    #[serde(default)]
+    pub auto_think: bool,
+    #[serde(default)]
+    pub empty_output: EmptyOutput,
+    /// NPU cores the model file was converted for. rkllm fixes this at
+    /// conversion, so requests asking for more cores are routed by it.
+    pub npu_cores: Option<u32>,
+    /// `max_tokens` for requests that do not set one, must be positive.
+    pub max_tokens: Option<u32>,
+    /// Caps generation speed to keep passively cooled boards from throttling.
+    pub max_tokens_per_sec: Option<f32>,
+    /// Merge system messages into the first user message when the chat
+    /// template has no system role.
+    #[serde(default = "default_fold_system_messages")]
+    pub fold_system_messages: bool,
+    /// Quantizations to look for among the repo's `.rkllm` files, in order of
+    /// preference, e.g. `["w4a16", "w8a8"]`. Unset keeps `model.rkllm`.
+    pub quantization: Option<Vec<String>>,
+    /// Compare the tokenizer with the model's `vocab_size` at load.
+    #[serde(default)]
+    pub vocab_check: VocabCheck,
+    /// Order in which a busy instance runs waiting streaming and
+    /// non-streaming requests.
+    #[serde(default)]
+    pub queue_fairness: QueueFairness,
+    /// Ends a generation stuck repeating an n-gram, unset never does.
+    pub loop_detection: Option<LoopDetection>,
+    /// The file [`resolve_model_file`](Self::resolve_model_file) picked.
+    #[serde(skip)]
+    model_file: Option<String>,
+}
+
+impl SimpleLLMConfig {
+    /// Checks values serde cannot, a failing config is treated as malformed.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.timeout_secs == Some(0) {
+            return Err("timeout_secs must be positive".to_owned());
+        }
+        if self.npu_cores == Some(0) {
+            return Err("npu_cores must be positive".to_owned());
+        }
+        if self.max_tokens == Some(0) {
+            return Err("max_tokens must be positive".to_owned());
+        }
+        if self.max_tokens_per_sec.is_some_and(|rate| !(rate > 0.0 && rate.is_finite())) {
+            return Err("max_tokens_per_sec must be a positive number".to_owned());
+        }
+        if self.quantization.as_ref().is_some_and(|quantization| quantization.is_empty()) {
+            return Err("quantization must list at least one quantization".to_owned());
+        }
+        if let Some(loop_detection) = &self.loop_detection {
+            loop_detection.validate()?;
+        }
+        Ok(())
+    }
+
+    /// Settings the chat handler applies by model name before it picks an
+    /// instance, which configs merged under one name must agree on.
+    pub fn request_limit_conflicts(&self, other: &SimpleLLMConfig) -> Vec<&'static str> {
+        [
+            ("max_messages", self.max_messages != other.max_messages),
+            ("max_tokens", self.max_tokens != other.max_tokens),
+            ("max_output_bytes", self.max_output_bytes != other.max_output_bytes),
+            ("timeout_secs", self.timeout_secs != other.timeout_secs),
+            ("capabilities", self.capabilities != other.capabilities),
+            ("prompt_cache_dir", self.prompt_cache_dir != other.prompt_cache_dir),
+            ("empty_output", self.empty_output != other.empty_output),
+        ]
+        .into_iter()
+        .filter_map(|(field, differs)| differs.then_some(field))
+        .collect()
+    }
+
+    /// Picks the `.rkllm` file to load from the repo once, asking Hugging
+    /// Face for the file list, so the cache check and init agree on it.
+    pub fn resolve_model_file(&mut self) -> Result<(), String> {
+        self.model_file = Some(self.find_model_file()?);
+        Ok(())
+    }
+
+    /// The resolved model file, looked up now if it was not resolved yet.
+    pub fn model_file(&self) -> Result<String, String> {
+        match &self.model_file {
+            Some(file) => Ok(file.clone()),
+            None => self.find_model_file(),
+        }
+    }
+
+    /// The `.rkllm` file to load from the repo, see `quantization`. Without a
+    /// preference `model.rkllm` wins, then [`DEFAULT_QUANTIZATION_ORDER`].
+    fn find_model_file(&self) -> Result<String, String> {
+        let listing = Api::new()
+            .and_then(|api| api.model(self.modle_path.clone()).info())
+            .map(|info| info.siblings.into_iter().map(|file| file.rfilename).collect::<Vec<_>>());
+        let Some(preference) = &self.quantization else {
+            let files = match listing {
+                Ok(files) => files,
+                // Offline, keep what earlier versions always loaded
+                Err(_) => return Ok(DEFAULT_MODEL_FILE.to_owned()),
+            };
+            let rkllm = files.iter().filter(|file| file.ends_with(".rkllm")).collect::<Vec<_>>();
+            return match rkllm.as_slice() {
+                [] => Ok(DEFAULT_MODEL_FILE.to_owned()),
+                _ if files.iter().any(|file| file == DEFAULT_MODEL_FILE) => Ok(DEFAULT_MODEL_FILE.to_owned()),
+                [only] => Ok((*only).clone()),
+                _ => pick_rkllm_file(&self.modle_path, &files, DEFAULT_QUANTIZATION_ORDER),
+            };
+        };
+        let files = listing.map_err(|e| format!("Failed to list the files of {}: {}", self.modle_path, e))?;
+        let preference = preference.iter().map(String::as_str).collect::<Vec<_>>();
+        pick_rkllm_file(&self.modle_path, &files, &preference)
+    }
+
+    /// File backing the named prompt cache. Caches sit in a directory per model
+    /// name and names cannot contain separators, so a request can only reach
+    /// caches saved by this model.
+    pub fn prompt_cache_file(&self, name: &str) -> Result<PathBuf, String> {
+        let Some(dir) = &self.prompt_cache_dir else {
+            return Err(format!("Model {} has no prompt_cache_dir configured", self.modle_name));
+        };
+        let valid = !name.is_empty()
+            && name.len() <= 64
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid {
+            return Err("Prompt cache names must be 1-64 characters of A-Z, a-z, 0-9, - and _".to_owned());
+        }
+        Ok(Path::new(dir).join(&self.modle_name).join(format!("{}.cache", name)))
+    }
+}
+
+/// What happens when the tokenizer does not fit the model's vocabulary.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VocabCheck {
+    /// Fail loading the model.
+    #[default]
+    Error,
+    /// Log the mismatch and load anyway.
+    Warn,
+    Off,
+}
+
+/// Models pad their embedding table past the tokenizer's last id, e.g. Qwen2
+/// has 151936 rows for 151665 ids. A bigger gap means another tokenizer.
+const VOCAB_PADDING_SLACK: f64 = 0.1;
+
+/// Checks that `tokenizer_ids` (highest token id plus one) fit the model's
+/// `vocab_size` from `config.json`.
+fn check_vocab(model: &str, tokenizer_ids: usize, vocab_size: usize) -> Result<(), String> {
+    if tokenizer_ids > vocab_size {
+        return Err(format!(
+            "The tokenizer of {} has token ids up to {} but the model's vocabulary has only {} entries, so it probably belongs to another model",
+            model,
+            tokenizer_ids - 1,
+            vocab_size
+        ));
+    }
+    if vocab_size as f64 > tokenizer_ids as f64 * (1.0 + VOCAB_PADDING_SLACK) {
+        return Err(format!(
+            "The model {} has a vocabulary of {} entries but its tokenizer only {}, so the tokenizer probably belongs to another model",
+            model, vocab_size, tokenizer_ids
+        ));
+    }
+    Ok(())
+}
+
+/// `vocab_size` from the repo's `config.json`, which converted repos often lack.
+fn model_vocab_size(repo: &ApiRepo) -> Option<usize> {
+    let path = repo.get("config.json").ok()?;
+    let config: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(path).ok()?).ok()?;
+    // Multimodal configs nest the language model's
+    let vocab_size = config.get("vocab_size").or_else(|| config.get("text_config")?.get("vocab_size"))?;
+    vocab_size.as_u64().map(|size| size as usize)
 }
 
+/// What repos converted for this server name their model file.
+pub const DEFAULT_MODEL_FILE: &str = "model.rkllm";
+
 fn default_legacy() -> bool {
     true
 }
 
+fn default_fold_system_messages() -> bool {
+    true
+}
+
+fn default_max_messages() -> usize {
+    128
+}
+
+fn default_max_output_bytes() -> usize {
+    1024 * 1024
+}
+
+/// rkllm does not document `rkllm_init` as thread-safe, so concurrent loaders
+/// take turns for it while downloads and tokenizer loading run in parallel.
+static RKLLM_INIT: Mutex<()> = Mutex::new(());
+static SET_LIBRARY_PATH: Once = Once::new();
+
 #[derive(Debug)]
 pub struct SimpleRkLLM {
     handle: LLMHandle,
-    atoken: AutoTokenizer,
+    /// Held for the whole `run` call.
+    destroyed: HandleGuard,
+    /// Shared rather than cloned per request, the chat template is several KB.
+    atoken: Arc<AutoTokenizer>,
+    /// Loaded from `tokenizer.json` when the repo has one, only used for debug output.
+    tokenizer: Option<Tokenizer>,
     infer_params: RKLLMInferParam,
+    /// See [`model_file_id`], prompt caches from other model files are ignored.
+    model_file_id: Option<String>,
+    /// The config's `cache_path`, saved by the worker and loaded once saved.
+    config_cache: Option<ConfigCache>,
     config: SimpleLLMConfig,
+    /// Set at init when `fold_system_messages` is on and the template drops system turns.
+    fold_system: bool,
+    details: ModelDetails,
+    worker: Worker,
+}
+
+/// A prompt cache is only reused by the model file that saved it, whose id is
+/// recorded next to the cache as `<cache>.model`.
+fn cache_stamp(cache: &Path) -> PathBuf {
+    let mut stamp = cache.as_os_str().to_owned();
+    stamp.push(".model");
+    PathBuf::from(stamp)
+}
+
+fn cache_matches(cache: &Path, model_file_id: Option<&str>) -> bool {
+    model_file_id.is_some_and(|id| {
+        cache.exists() && std::fs::read_to_string(cache_stamp(cache)).is_ok_and(|stamp| stamp.trim() == id)
+    })
+}
+
+fn stamp_cache(cache: &Path, model_file_id: Option<&str>) {
+    if let Some(id) = model_file_id {
+        if let Err(e) = std::fs::write(cache_stamp(cache), id) {
+            println!("Failed to stamp prompt cache {}: {}", cache.display(), e);
+        }
+    }
+}
+
+/// Shared with the worker so a save only counts once its run succeeded.
+#[derive(Debug, Clone)]
+struct ConfigCache {
+    path: String,
+    state: Arc<Mutex<CacheState>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CacheState {
+    /// Missing or stale, the next run without a `prompt_cache` saves it.
+    Unsaved,
+    /// A queued or running generation is saving it.
+    Saving,
+    /// Saved and loaded into the handle.
+    Loaded,
+}
+
+impl CacheState {
+    /// Hands the save to one generation at a time.
+    fn claim(&mut self) -> bool {
+        let claimed = *self == CacheState::Unsaved;
+        if claimed {
+            *self = CacheState::Saving;
+        }
+        claimed
+    }
+
+    /// A failed save is tried again by the next generation.
+    fn finish(&mut self, saved: bool) {
+        *self = if saved { CacheState::Loaded } else { CacheState::Unsaved };
+    }
+}
+
+impl ConfigCache {
+    fn state(&self) -> std::sync::MutexGuard<'_, CacheState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn save_cache_params(cache_path: &str) -> RKLLMPromptCacheParam {
+    RKLLMPromptCacheParam {
+        save_prompt_cache: true,
+        prompt_cache_path: cache_path.to_owned(),
+    }
+}
+
+impl SimpleRkLLM {
+    /// Describes this instance for the models endpoints, `instances` is left at 0.
+    pub fn model_details(&self) -> ModelDetails {
+        self.details.clone()
+    }
+
+    pub fn npu_cores(&self) -> Option<u32> {
+        self.config.npu_cores
+    }
+
+    /// Generations queued or running on this instance, kept up to date.
+    pub fn queued(&self) -> Arc<AtomicUsize> {
+        self.worker.queued()
+    }
+}
+
+/// Reads the repo's `tokenizer_config.json` with `legacy` set, only when it
+/// is missing unless `force`. The hub cache is left untouched.
+fn tokenizer_with_legacy(
+    repo: &ApiRepo,
+    legacy: bool,
+    force: bool,
+) -> Result<AutoTokenizer, Box<dyn std::error::Error + Send + Sync>> {
+    let path = repo.get("tokenizer_config.json")?;
+    let mut tokenizer_config: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+    if let Some(fields) = tokenizer_config.as_object_mut() {
+        if force || !fields.contains_key("legacy") {
+            fields.insert("legacy".to_owned(), legacy.into());
+        }
+    }
+    Ok(serde_json::from_value(tokenizer_config)?)
+}
+
+/// Marker sent as a system turn to see whether the template renders it.
+const SYSTEM_PROBE: &str = "llmserver-system-probe";
+
+/// Whether the chat template keeps system turns. Templates without a system
+/// role either fail on one or silently leave it out.
+fn template_has_system_role(atoken: &AutoTokenizer) -> bool {
+    let probe = vec![
+        DefaultPromptMessage::new("system", SYSTEM_PROBE),
+        DefaultPromptMessage::new("user", "hi"),
+    ];
+    atoken
+        .apply_chat_template(probe, true)
+        .is_ok_and(|prompt| prompt.contains(SYSTEM_PROBE))
+}
+
+/// Prepends every system turn to the first user turn, or turns them into one
+/// user turn when there is none.
+fn fold_system_turns(turns: Vec<(&'static str, String)>) -> Vec<(&'static str, String)> {
+    let (system, mut rest): (Vec<_>, Vec<_>) = turns.into_iter().partition(|(role, _)| *role == "system");
+    let system = system.into_iter().map(|(_, content)| content).collect::<Vec<_>>().join("\n\n");
+    match rest.iter_mut().find(|(role, _)| *role == "user") {
+        Some((_, content)) => *content = format!("{}\n\n{}", system, content),
+        None => rest.insert(0, ("user", system)),
+    }
+    rest
 }
 
 impl Actor for SimpleRkLLM {
@@ -42,47 +423,198 @@ impl Actor for SimpleRkLLM {
 }
 
 impl actix::Handler<ProcessMessages> for SimpleRkLLM {
-    type Result = Result<Pin<Box<dyn futures::Stream<Item = String> + Send + 'static>>, ()>;
+    type Result = Result<Generation, ApiError>;
 
     fn handle(&mut self, msg: ProcessMessages, _ctx: &mut Self::Context) -> Self::Result {
+        // A held lock means a generation is running, so the handle is alive
+        if self.destroyed.is_destroyed() || !self.worker.alive() {
+            return Err(ApiError::DeadInstance);
+        }
         let (tx, rx) = tokio::sync::mpsc::channel(64);
-        let atoken = self.atoken.clone();
-        let prompt = msg
+        let atoken = Arc::clone(&self.atoken);
+        let templating = Instant::now();
+        let mut turns = msg
             .messages
             .iter()
             .map(|a| {
                 let content = match &a.content {
-                    Some(crate::Content::String(s)) => s,
-                    Some(crate::Content::Array(items)) => &items.join(""),
-                    None => "", // 老實說不應該發生
+                    Some(crate::Content::String(s)) => s.clone(),
+                    Some(crate::Content::Array(items)) => items.join(""),
+                    None => String::new(), // 老實說不應該發生
                 };
-                DefaultPromptMessage::new(to_variant_name(&a.role).unwrap(), &content)
+                (to_variant_name(&a.role).unwrap(), content)
             })
             .collect::<Vec<_>>();
+        if self.fold_system && turns.iter().any(|(role, _)| *role == "system") {
+            println!("Folding system message into the first user message for {}", self.config.modle_name);
+            turns = fold_system_turns(turns);
+        }
+        let prompt = turns
+            .iter()
+            .map(|(role, content)| DefaultPromptMessage::new(role, content))
+            .collect::<Vec<_>>();
 
-        let mut input = match atoken.apply_chat_template(prompt, true) {
-            Ok(parsed) => parsed,
-            Err(_) => {
-                println!("apply_chat_template failed.");
-                "".to_owned()
+        let mut input = atoken
+            .apply_chat_template(prompt, true)
+            .map_err(|e| ApiError::TokenizerFailed(e.to_string()))?;
+        let think = msg.think.unwrap_or_else(|| {
+            if self.config.auto_think {
+                looks_complex(&msg.messages)
+            } else {
+                self.config.think
             }
-        };
-        // TODO: 用參數判斷要不要think
-        if !self.config.think {
+        });
+        if !think {
             input += "\n\n</think>\n\n";
         }
+        let starts_in_reasoning = input.trim_end().ends_with("<think>");
 
-        let handle = self.handle.clone();
-        let infer_params_cloned = self.infer_params.clone();
-        actix_web::rt::spawn(async move {
-            let cb = CallbackSendSelfChannel { sender: Some(tx) };
-            // TODO: Maybe someday should have good error handling
-            let _ = handle.run(RKLLMInput::Prompt(input), Some(infer_params_cloned), cb);
+        let prompt_token_ids = match (&self.tokenizer, msg.debug) {
+            // The chat template already inserted the special tokens
+            (Some(tokenizer), true) => tokenizer
+                .encode(input.as_str(), false)
+                .map(|encoding| encoding.get_ids().to_vec())
+                .ok(),
+            _ => None,
+        };
+        let template_time = templating.elapsed();
+
+        let normalization = msg
+            .normalization
+            .or(self.config.output_normalization)
+            .filter(|n| !n.is_noop());
+        let handle = self.handle;
+        let destroyed = self.destroyed.clone();
+        let mut infer_params_cloned = self.infer_params.clone();
+        let prompt_cache = msg
+            .prompt_cache
+            .map(|name| self.config.prompt_cache_file(&name))
+            .transpose()
+            .map_err(|message| ApiError::InvalidValue {
+                param: "prompt_cache",
+                message,
+            })?;
+        let bypass_cache = msg.bypass_prompt_cache;
+        let config_cache = self.config_cache.clone();
+        // Claimed at dispatch so generations queued behind this one do not save it too
+        let save_config_cache = prompt_cache.is_none()
+            && !bypass_cache
+            && config_cache.as_ref().is_some_and(|cache| cache.state().claim());
+        let model_file_id = self.model_file_id.clone();
+        let (hidden_tx, hidden_states) = if msg.hidden_states {
+            infer_params_cloned.mode = RKLLMInferMode::InferGetLastHiddenLayer;
+            let (hidden_tx, hidden_rx) = tokio::sync::oneshot::channel();
+            (Some(hidden_tx), Some(hidden_rx))
+        } else {
+            (None, None)
+        };
+        let pacer = self.config.max_tokens_per_sec.map(Pacer::new);
+        let hit_max_tokens = Arc::new(AtomicBool::new(false));
+        let cancel = Arc::new(AtomicBool::new(false));
+        let cancelled = cancel.clone();
+        let max_tokens = msg.max_tokens.map(|max| (max, hit_max_tokens.clone()));
+        let loop_detector = self.config.loop_detection.map(LoopDetector::new);
+        let model_name = self.config.modle_name.clone();
+        let kind = if msg.stream { JobKind::Streaming } else { JobKind::NonStreaming };
+        let dispatched = self.worker.dispatch(kind, move || {
+            // Taken before the handle lock so waiting never blocks shutdown
+            let _npu = futures::executor::block_on(crate::npu::acquire());
+            // Shutdown winning the race drops tx, which ends the stream
+            destroyed.run(move || {
+                let cb = CallbackSendSelfChannel {
+                    sender: Some(tx),
+                    hidden_sender: hidden_tx,
+                    normalization,
+                    handle,
+                    tokens: 0,
+                    max_tokens,
+                    pacer,
+                    loop_detector,
+                    model_name,
+                    cancel: cancelled,
+                    aborted: false,
+                };
+                // Loading, running and releasing all happen under the lock, so the
+                // next request on this handle never inherits the cache
+                let mut loaded_cache = false;
+                let mut saved_cache = None;
+                if let (true, Some(cache)) = (save_config_cache, &config_cache) {
+                    infer_params_cloned.prompt_cache_params = Some(save_cache_params(&cache.path));
+                    saved_cache = Some(PathBuf::from(&cache.path));
+                }
+                if let Some(path) = &prompt_cache {
+                    let path_str = path.to_string_lossy();
+                    if cache_matches(path, model_file_id.as_deref()) {
+                        match handle.load_prompt_cache(&path_str) {
+                            Ok(()) => loaded_cache = true,
+                            Err(e) => println!("Failed to load prompt cache {}: {}", path_str, e),
+                        }
+                        infer_params_cloned.prompt_cache_params = None;
+                    } else {
+                        if let Some(dir) = path.parent() {
+                            let _ = std::fs::create_dir_all(dir);
+                        }
+                        infer_params_cloned.prompt_cache_params = Some(save_cache_params(&path_str));
+                        saved_cache = Some(path.clone());
+                    }
+                }
+                // Finished saves only change state under this lock, so this stays accurate
+                let static_cache = config_cache
+                    .as_ref()
+                    .filter(|cache| *cache.state() == CacheState::Loaded)
+                    .map(|cache| cache.path.as_str());
+                let unload_static = bypass_cache && static_cache.is_some();
+                if unload_static {
+                    let _ = handle.release_prompt_cache();
+                }
+                // TODO: Maybe someday should have good error handling
+                let result = handle.run(RKLLMInput::Prompt(input), Some(infer_params_cloned), cb);
+                if let (Ok(()), Some(path)) = (&result, &saved_cache) {
+                    stamp_cache(path, model_file_id.as_deref());
+                }
+                if loaded_cache {
+                    let _ = handle.release_prompt_cache();
+                }
+                // Releasing also dropped the config cache, put it back
+                if loaded_cache || unload_static {
+                    if let Some(cache_path) = static_cache {
+                        let _ = handle.load_prompt_cache(cache_path);
+                    }
+                }
+                if let (true, Some(cache)) = (save_config_cache, &config_cache) {
+                    let saved = match result {
+                        Ok(()) => match handle.load_prompt_cache(&cache.path) {
+                            Ok(()) => {
+                                println!("Saved and loaded prompt cache {}", cache.path);
+                                true
+                            }
+                            Err(e) => {
+                                println!("Failed to load saved prompt cache {}: {}", cache.path, e);
+                                false
+                            }
+                        },
+                        Err(_) => false,
+                    };
+                    cache.state().finish(saved);
+                }
+            });
         });
+        if !dispatched {
+            return Err(ApiError::DeadInstance);
+        }
 
         // 將 Receiver 轉換為 Stream
         let stream = ReceiverStream::new(rx);
-        Ok(Box::pin(stream))
+        Ok(Generation {
+            stream: Box::pin(stream),
+            prompt_token_ids,
+            hidden_states,
+            system_fingerprint: self.details.system_fingerprint.clone(),
+            template_time,
+            starts_in_reasoning,
+            hit_max_tokens,
+            cancel,
+        })
     }
 }
 
@@ -90,17 +622,35 @@ impl actix::Handler<ShutdownMessages> for SimpleRkLLM {
     type Result = Result<(), ()>;
 
     fn handle(&mut self, _: ShutdownMessages, _: &mut Self::Context) -> Self::Result {
-        // TODO: Maybe someday should have good error handling
-        let _ = self.handle.destroy();
+        // rkllm_is_running returns 0 while a task is running
+        if self.handle.is_running().is_ok() {
+            let _ = self.handle.abort();
+        }
+        // Wait for the aborted generation to leave `run` before destroying
+        self.destroyed.destroy(|| {
+            // TODO: Maybe someday should have good error handling
+            let _ = self.handle.destroy();
+        });
         Ok(())
     }
 }
 
+impl actix::Handler<Ping> for SimpleRkLLM {
+    type Result = bool;
+
+    fn handle(&mut self, _: Ping, _: &mut Self::Context) -> Self::Result {
+        // Held during generation, which is not a sign of trouble
+        !self.destroyed.is_destroyed() && self.worker.alive()
+    }
+}
+
 impl AIModel for SimpleRkLLM {
     type Config = SimpleLLMConfig;
     fn init(config: &SimpleLLMConfig) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
         // Set environment variable for sentencepiece to find the correct library
-        std::env::set_var("LD_LIBRARY_PATH", "/usr/local/lib:".to_string() + &std::env::var("LD_LIBRARY_PATH").unwrap_or_default());
+        SET_LIBRARY_PATH.call_once(|| {
+            std::env::set_var("LD_LIBRARY_PATH", "/usr/local/lib:".to_string() + &std::env::var("LD_LIBRARY_PATH").unwrap_or_default());
+        });
         
         let mut param = RKLLMParam {
             ..Default::default()
@@ -109,65 +659,153 @@ impl AIModel for SimpleRkLLM {
         // Model loading with better error handling
         let api = Api::new().map_err(|e| format!("Failed to initialize HF API: {}", e))?;
         let repo = api.model(config.modle_path.clone());
-        let binding = repo.get("model.rkllm").map_err(|e| format!("Failed to get model file: {}", e))?;
+        let model_file = config.model_file()?;
+        println!("Loading {} from {}", model_file, config.modle_path);
+        let binding = repo.get(&model_file).map_err(|e| format!("Failed to get model file: {}", e))?;
         let modle_path = binding.to_string_lossy();
         let c_str = CString::new(modle_path.as_ref()).unwrap();
         param.model_path = c_str.as_ptr();
 
-        // Try to initialize the model with custom error handling
-        let handle = match rkllm_init(&mut param) {
-            Ok(h) => h,
+        let mut details = ModelDetails::new(&config.modle_name, &config.modle_path, ModelType::LLM);
+        details.context_length = Some(param.max_context_len);
+        details.sampling = Some(SamplingDefaults {
+            max_new_tokens: param.max_new_tokens,
+            top_k: param.top_k,
+            top_p: param.top_p,
+            temperature: param.temperature,
+            repeat_penalty: param.repeat_penalty,
+            frequency_penalty: param.frequency_penalty,
+            presence_penalty: param.presence_penalty,
+        });
+        details.think = Some(config.think);
+        details.model.capabilities = Some(config.capabilities);
+        details.revision = repo.info().ok().map(|info| info.sha);
+        // Timeouts, limits and cache paths do not change what gets generated
+        let generation_config = (
+            &details.sampling,
+            details.context_length,
+            config.think,
+            config.auto_think,
+            config.output_normalization,
+        );
+        let model_file_id = match model_file_id(&binding) {
+            Ok(id) => Some(id),
             Err(e) => {
-                // If the error mentions "missing field `legacy`", try to work around it
-                if e.to_string().contains("missing field `legacy`") {
-                    println!("Warning: Detected 'missing field legacy' error, attempting to continue anyway");
-                    // Try to initialize with a different approach or return a fallback
-                    return Err("Model initialization failed due to a missing 'legacy' field in the model config. This may be due to a version mismatch between your model and the rkllm-rs library. Try using a different model or updating the rkllm-rs dependency.".into());
-                } else {
-                    return Err(e);
+                println!("Warning: Failed to identify {}, prompt caches will not be reused: {}", modle_path, e);
+                None
+            }
+        };
+        details.system_fingerprint = model_file_id
+            .as_deref()
+            .and_then(|id| system_fingerprint(id, &generation_config).ok());
+
+        let tokenizer = match repo.get("tokenizer.json").map(Tokenizer::from_file) {
+            Ok(Ok(tokenizer)) => Some(tokenizer),
+            Ok(Err(e)) => {
+                println!("Warning: Failed to load tokenizer.json, prompt token ids unavailable: {}", e);
+                None
+            }
+            Err(_) => None,
+        };
+
+        // Checked before rkllm_init, a mismatched pair is not worth loading on the NPU
+        if config.vocab_check != VocabCheck::Off {
+            let tokenizer_ids = tokenizer
+                .as_ref()
+                .and_then(|tokenizer| tokenizer.get_vocab(true).into_values().max())
+                .map(|id| id as usize + 1);
+            match (tokenizer_ids, model_vocab_size(&repo)) {
+                (Some(tokenizer_ids), Some(vocab_size)) => {
+                    println!(
+                        "Vocabulary of {}: tokenizer {} ids, model {} entries",
+                        config.modle_name, tokenizer_ids, vocab_size
+                    );
+                    match check_vocab(&config.modle_name, tokenizer_ids, vocab_size) {
+                        Err(e) if config.vocab_check == VocabCheck::Error => {
+                            return Err(format!("{}. Set \"vocab_check\": \"warn\" to load it anyway.", e).into());
+                        }
+                        Err(e) => println!("Warning: {}", e),
+                        Ok(()) => {}
+                    }
                 }
+                (None, _) => println!("Skipping the vocabulary check of {}, it has no tokenizer.json", config.modle_name),
+                (_, None) => println!(
+                    "Skipping the vocabulary check of {}, its config.json has no vocab_size",
+                    config.modle_name
+                ),
             }
+        }
+
+        let handle = {
+            let _guard = RKLLM_INIT.lock().unwrap_or_else(|e| e.into_inner());
+            rkllm_init(&mut param)?
         };
         
-        // Initialize tokenizer with custom error handling
-        let atoken = match AutoTokenizer::from_pretrained(config.modle_path.clone(), None) {
-            Ok(tokenizer) => tokenizer,
-            Err(e) => {
-                if e.to_string().contains("missing field `legacy`") {
-                    println!("Warning: Detected missing field 'legacy' error in tokenizer initialization");
-                    eprintln!("This error is likely due to a mismatch between your model configuration ");
-                    eprintln!("and the autotokenizer library after adding tool calling support.");
-                    eprintln!("\nPossible solutions:\n");
-                    eprintln!("1. Try a different model that's compatible with your current server version");
-                    eprintln!("2. Update the rkllm-rs and autotokenizer crates to versions that support your model");
-                    eprintln!("3. Downgrade your server code to a version before adding tool calling support");
-                    return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, 
-                        "Tokenizer initialization failed: model config is incompatible with current autotokenizer version")));
-                } else {
-                    return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, 
-                        format!("Tokenizer initialization failed: {}", e))));
+        // Initialize tokenizer, filling in `legacy` for configs written before the field existed
+        let atoken = if config.force_legacy {
+            tokenizer_with_legacy(&repo, config.legacy, true)
+        } else {
+            match AutoTokenizer::from_pretrained(config.modle_path.clone(), None) {
+                Err(e) if e.to_string().contains("missing field `legacy`") => {
+                    println!(
+                        "Tokenizer config of {} has no legacy field, loading it with legacy={}",
+                        config.modle_path, config.legacy
+                    );
+                    tokenizer_with_legacy(&repo, config.legacy, false)
                 }
+                result => result,
             }
-        };
+        }
+        .map_err(|e| std::io::Error::other(format!("Tokenizer initialization failed: {}", e)))?;
+
+        // A cache saved from this model file by an earlier run is loaded once and
+        // kept, otherwise the first generation saves a new one
+        let config_cache = config.cache_path.as_ref().map(|cache_path| {
+            let state = if !cache_matches(Path::new(cache_path), model_file_id.as_deref()) {
+                CacheState::Unsaved
+            } else {
+                match handle.load_prompt_cache(cache_path) {
+                    Ok(()) => {
+                        println!("Loaded prompt cache {}", cache_path);
+                        CacheState::Loaded
+                    }
+                    Err(e) => {
+                        println!("Failed to load prompt cache {}, saving a new one: {}", cache_path, e);
+                        CacheState::Unsaved
+                    }
+                }
+            };
+            ConfigCache {
+                path: cache_path.to_owned(),
+                state: Arc::new(Mutex::new(state)),
+            }
+        });
+        let fold_system = config.fold_system_messages && !template_has_system_role(&atoken);
+        if fold_system {
+            println!(
+                "Chat template of {} has no system role, system messages will be folded into the first user message",
+                config.modle_name
+            );
+        }
 
         let infer_params = RKLLMInferParam {
             mode: RKLLMInferMode::InferGenerate,
             lora_params: None,
-            prompt_cache_params: if let Some(cache_path) = &config.cache_path {
-                Some(RKLLMPromptCacheParam {
-                    save_prompt_cache: true,
-                    prompt_cache_path: cache_path.to_owned(),
-                })
-            } else {
-                None
-            },
+            prompt_cache_params: None,
         };
 
         Ok(SimpleRkLLM {
             handle,
-            atoken,
+            destroyed: HandleGuard::default(),
+            atoken: Arc::new(atoken),
+            tokenizer,
             infer_params,
+            model_file_id,
+            config_cache,
             config: config.clone(),
+            fold_system,
+            details,
+            worker: Worker::spawn(&config.modle_name, config.queue_fairness)?,
         })
     }
 }
@@ -176,15 +814,118 @@ impl LLM for SimpleRkLLM {}
 
 struct CallbackSendSelfChannel {
     sender: Option<tokio::sync::mpsc::Sender<String>>,
+    hidden_sender: Option<tokio::sync::oneshot::Sender<HiddenStates>>,
+    normalization: Option<OutputNormalization>,
+    handle: LLMHandle,
+    tokens: u32,
+    /// The request's token limit and the flag raised when it is reached.
+    max_tokens: Option<(u32, Arc<AtomicBool>)>,
+    pacer: Option<Pacer>,
+    loop_detector: Option<LoopDetector>,
+    model_name: String,
+    /// Raised by the chat handler when it gives up on the request.
+    cancel: Arc<AtomicBool>,
+    aborted: bool,
+}
+
+/// Spaces tokens at least `1 / rate` seconds apart. It sleeps in the rkllm
+/// callback, which stalls the NPU itself instead of only delaying delivery.
+struct Pacer {
+    interval: Duration,
+    next: Instant,
+}
+
+impl Pacer {
+    fn new(tokens_per_sec: f32) -> Self {
+        Pacer {
+            interval: Duration::from_secs_f32(1.0 / tokens_per_sec),
+            next: Instant::now(),
+        }
+    }
+
+    fn wait(&mut self) {
+        let now = Instant::now();
+        if self.next > now {
+            std::thread::sleep(self.next - now);
+        }
+        self.next = self.next.max(now) + self.interval;
+    }
+}
+impl CallbackSendSelfChannel {
+    fn send(&mut self, mut text: String) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        loop {
+            match sender.try_send(text) {
+                Ok(()) => return,
+                Err(TrySendError::Full(back)) => {
+                    text = back;
+                    std::thread::yield_now();
+                }
+                // Client went away or stopped reading, drop the rest
+                Err(TrySendError::Closed(_)) => break,
+            }
+        }
+        self.sender = None;
+    }
+
+    /// Ends the stream and aborts the run, so the instance is free for the
+    /// next request instead of generating text nobody reads.
+    fn stop(&mut self) {
+        self.sender = None;
+        if !self.aborted {
+            self.aborted = true;
+            let _ = self.handle.abort();
+        }
+    }
 }
+
 impl RkllmCallbackHandler for CallbackSendSelfChannel {
     fn handle(&mut self, result: Option<RKLLMResult>, state: LLMCallState) {
         match state {
             LLMCallState::Normal => {
+                if self.cancel.load(Ordering::Relaxed) {
+                    self.stop();
+                }
+                // Stopped, or the client went away and nobody reads the rest
+                if self.sender.is_none() {
+                    self.stop();
+                    return;
+                }
                 if let Some(result) = result {
-                    if let Some(sender) = &self.sender {
-                        while sender.try_send(result.text.clone()).is_err() {
-                            std::thread::yield_now();
+                    if let Some(pacer) = &mut self.pacer {
+                        pacer.wait();
+                    }
+                    let looping = self.loop_detector.as_mut().and_then(|detector| {
+                        let n = detector.push(&result.text)?;
+                        Some((n, detector.tail(n)))
+                    });
+                    let text = match &self.normalization {
+                        Some(normalization) => normalization.apply(&result.text),
+                        None => result.text,
+                    };
+                    self.send(text);
+                    self.tokens += 1;
+                    if let Some((n, ngram)) = looping {
+                        if self.sender.is_some() {
+                            println!(
+                                "Warning: {} repeated the {}-token sequence {:?} in a loop after {} tokens, stopping",
+                                self.model_name, n, ngram, self.tokens
+                            );
+                            // Stopped like a normal end, the finish_reason stays `Stop`
+                            self.send(String::new());
+                            self.stop();
+                            return;
+                        }
+                    }
+                    if let Some((max, hit)) = &self.max_tokens {
+                        if self.tokens >= *max && self.sender.is_some() {
+                            hit.store(true, Ordering::Relaxed);
+                            // An empty piece closes the stream with a finish_reason
+                            self.send(String::new());
+                            // rkllm has no per-run token limit, stop it from here
+                            self.stop();
                         }
                     }
                 }
@@ -194,7 +935,111 @@ impl RkllmCallbackHandler for CallbackSendSelfChannel {
                 drop(self.sender.take());
             }
             LLMCallState::Error => {}
-            LLMCallState::GetLastHiddenLayer => {}
+            LLMCallState::GetLastHiddenLayer => {
+                let (Some(result), Some(hidden_sender)) = (result, self.hidden_sender.take()) else {
+                    return;
+                };
+                let layer = result.last_hidden_layer;
+                if layer.hidden_states.is_null() || layer.embd_size <= 0 || layer.num_tokens <= 0 {
+                    return;
+                }
+                let (embd_size, num_tokens) = (layer.embd_size as usize, layer.num_tokens as usize);
+                // The buffer is owned by rkllm and only valid during this callback, so copy it out
+                let flat = unsafe { std::slice::from_raw_parts(layer.hidden_states, embd_size * num_tokens) };
+                let _ = hidden_sender.send(HiddenStates {
+                    embd_size,
+                    num_tokens,
+                    data: flat.chunks(embd_size).map(|row| row.to_vec()).collect(),
+                });
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_cache_saves_are_retried() {
+        let mut state = CacheState::Unsaved;
+        assert!(state.claim());
+        assert!(!state.claim(), "a second generation saved while the first was running");
+        state.finish(false);
+        assert_eq!(state, CacheState::Unsaved);
+        assert!(state.claim());
+        state.finish(true);
+        assert_eq!(state, CacheState::Loaded);
+        assert!(!state.claim());
+    }
+
+    #[test]
+    fn merged_configs_agree_on_request_limits() {
+        let first = SimpleLLMConfig {
+            max_tokens: Some(256),
+            npu_cores: Some(1),
+            ..Default::default()
+        };
+        let same_limits = SimpleLLMConfig {
+            npu_cores: Some(3),
+            ..first.clone()
+        };
+        assert!(first.request_limit_conflicts(&same_limits).is_empty());
+        let other_limits = SimpleLLMConfig {
+            max_tokens: Some(512),
+            timeout_secs: Some(10),
+            ..first.clone()
+        };
+        assert_eq!(first.request_limit_conflicts(&other_limits), ["max_tokens", "timeout_secs"]);
+    }
+
+    fn tokenizer(chat_template: &str) -> AutoTokenizer {
+        serde_json::from_value(serde_json::json!({
+            "clean_up_tokenization_spaces": false,
+            "tokenizer_class": "PreTrainedTokenizerFast",
+            "chat_template": chat_template,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn detects_templates_without_a_system_role() {
+        let keeps_system = tokenizer("{% for m in messages %}<|{{ m.role }}|>{{ m.content }}{% endfor %}");
+        assert!(template_has_system_role(&keeps_system));
+        // Gemma style: only user and model turns
+        let drops_system = tokenizer("{% for m in messages %}{% if m.role != 'system' %}{{ m.content }}{% endif %}{% endfor %}");
+        assert!(!template_has_system_role(&drops_system));
+        let rejects_system = tokenizer("{% for m in messages %}{% if m.role == 'system' %}{{ raise_exception('no system') }}{% endif %}{{ m.content }}{% endfor %}");
+        assert!(!template_has_system_role(&rejects_system));
+    }
+
+    #[test]
+    fn folds_system_turns_into_the_first_user_turn() {
+        let turns = vec![
+            ("system", "Be brief.".to_owned()),
+            ("user", "Hi".to_owned()),
+            ("assistant", "Hello".to_owned()),
+            ("system", "Answer in French.".to_owned()),
+            ("user", "Thanks".to_owned()),
+        ];
+        assert_eq!(
+            fold_system_turns(turns),
+            [
+                ("user", "Be brief.\n\nAnswer in French.\n\nHi".to_owned()),
+                ("assistant", "Hello".to_owned()),
+                ("user", "Thanks".to_owned()),
+            ]
+        );
+        assert_eq!(fold_system_turns(vec![("system", "Be brief.".to_owned())]), [("user", "Be brief.".to_owned())]);
+    }
+
+    #[test]
+    fn empty_output_defaults_to_warn() {
+        let config = |extra: &str| {
+            serde_json::from_str::<SimpleLLMConfig>(&format!(r#"{{"modle_path": "a/b", "modle_name": "b", "think": false{}}}"#, extra))
+        };
+        assert_eq!(config("").unwrap().empty_output, EmptyOutput::Warn);
+        assert_eq!(config(r#", "empty_output": "error""#).unwrap().empty_output, EmptyOutput::Error);
+        assert!(config(r#", "empty_output": "fail""#).is_err());
+    }
+}
@@ -0,0 +1,156 @@
+use crate::{Content, Message, Role};
+
+/// User turns at least this many characters long are treated as complex.
+const LONG_PROMPT_CHARS: usize = 280;
+
+/// Words hinting at math, code or multi-step reasoning, matched case-insensitively.
+const KEYWORDS: &[&str] = &[
+    "```", "prove", "proof", "solve", "calculate", "equation", "integral", "derivative",
+    "probability", "step by step", "algorithm", "complexity", "debug", "refactor",
+    "implement", "fn ", "def ", "function", "class ", "traceback", "證明", "证明", "計算",
+    "计算", "方程", "推導", "推导", "算法", "演算法", "程式", "代码", "程式碼", "為什麼",
+    "为什么", "步驟", "步骤",
+];
+
+/// Heuristic for `auto_think`: does the last user turn look like it needs
+/// reasoning? Cheap on purpose, it runs before every templated prompt and
+/// only trades latency against answer quality, never correctness.
+pub fn looks_complex(messages: &[Message]) -> bool {
+    let Some(text) = messages
+        .iter()
+        .rev()
+        .find(|m| matches!(m.role, Some(Role::User)))
+        .and_then(|m| m.content.as_ref())
+        .map(|content| match content {
+            Content::String(s) => s.to_lowercase(),
+            Content::Array(items) => items.join("").to_lowercase(),
+        })
+    else {
+        return false;
+    };
+    if text.chars().count() >= LONG_PROMPT_CHARS {
+        return true;
+    }
+    if KEYWORDS.iter().any(|keyword| text.contains(keyword)) {
+        return true;
+    }
+    // Arithmetic such as "12*7" or "3 + 4 ="
+    let operators = text.chars().filter(|c| matches!(c, '+' | '*' | '/' | '=' | '^' | '×' | '÷')).count();
+    operators >= 2 && text.chars().any(|c| c.is_ascii_digit())
+}
+
+const OPEN_TAG: &str = "<think>";
+const CLOSE_TAG: &str = "</think>";
+
+/// Splits streamed text at `<think>` and `</think>` into reasoning and content
+/// parts with the tags removed. A tail that may be the start of a tag split
+/// across tokens is held back until the next piece decides it.
+#[derive(Debug, Default)]
+pub struct ReasoningSplitter {
+    reasoning: bool,
+    pending: String,
+}
+
+impl ReasoningSplitter {
+    /// `reasoning` when the prompt already opened the think block.
+    pub fn new(reasoning: bool) -> Self {
+        ReasoningSplitter {
+            reasoning,
+            pending: String::new(),
+        }
+    }
+
+    /// `(is_reasoning, text)` parts ready to send, empty parts are skipped.
+    pub fn push(&mut self, piece: &str) -> Vec<(bool, String)> {
+        self.pending.push_str(piece);
+        let mut parts = Vec::new();
+        loop {
+            let tag = if self.reasoning { CLOSE_TAG } else { OPEN_TAG };
+            if let Some(at) = self.pending.find(tag) {
+                if at > 0 {
+                    parts.push((self.reasoning, self.pending[..at].to_owned()));
+                }
+                self.pending.drain(..at + tag.len());
+                self.reasoning = !self.reasoning;
+                continue;
+            }
+            // Tags are ASCII, so the held back tail always starts on a char boundary
+            let keep = (1..tag.len())
+                .rev()
+                .find(|&n| self.pending.ends_with(&tag[..n]))
+                .unwrap_or(0);
+            let ready = self.pending.len() - keep;
+            if ready > 0 {
+                parts.push((self.reasoning, self.pending.drain(..ready).collect()));
+            }
+            return parts;
+        }
+    }
+
+    /// Whatever was held back, once the stream is over.
+    pub fn finish(&mut self) -> Option<(bool, String)> {
+        (!self.pending.is_empty()).then(|| (self.reasoning, std::mem::take(&mut self.pending)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds `pieces` like streamed tokens and joins adjacent parts of the same kind.
+    fn split(reasoning: bool, pieces: &[&str]) -> Vec<(bool, String)> {
+        let mut splitter = ReasoningSplitter::new(reasoning);
+        let mut parts = pieces.iter().flat_map(|piece| splitter.push(piece)).collect::<Vec<_>>();
+        parts.extend(splitter.finish());
+        let mut joined = Vec::<(bool, String)>::new();
+        for (is_reasoning, text) in parts {
+            match joined.last_mut() {
+                Some((last, joined_text)) if *last == is_reasoning => joined_text.push_str(&text),
+                _ => joined.push((is_reasoning, text)),
+            }
+        }
+        joined
+    }
+
+    #[test]
+    fn tags_split_across_tokens() {
+        assert_eq!(
+            split(false, &["<th", "ink>Let me", " think</", "thi", "nk>The answer", " is 4."]),
+            [(true, "Let me think".to_owned()), (false, "The answer is 4.".to_owned())]
+        );
+        // Prompts that open the think block only stream the closing tag
+        assert_eq!(
+            split(true, &["hmm", "<", "/", "think", ">", "4"]),
+            [(true, "hmm".to_owned()), (false, "4".to_owned())]
+        );
+    }
+
+    #[test]
+    fn holds_back_only_what_may_be_a_tag() {
+        let mut splitter = ReasoningSplitter::new(true);
+        assert_eq!(splitter.push("a </th"), [(true, "a ".to_owned())]);
+        // Not a tag after all, the held back text goes out as reasoning
+        assert_eq!(splitter.push("is"), [(true, "</this".to_owned())]);
+    }
+
+    #[test]
+    fn missing_closing_tag() {
+        assert_eq!(split(true, &["still ", "thinking <"]), [(true, "still thinking <".to_owned())]);
+    }
+
+    #[test]
+    fn no_reasoning() {
+        assert_eq!(split(false, &["Plain ", "answer, 1 < 2."]), [(false, "Plain answer, 1 < 2.".to_owned())]);
+        let mut splitter = ReasoningSplitter::new(false);
+        assert!(splitter.push("").is_empty());
+        assert_eq!(splitter.finish(), None);
+    }
+
+    #[test]
+    fn multibyte_text_next_to_tags() {
+        assert_eq!(
+            split(false, &["<think>想", "一想</th", "ink>答案"]),
+            [(true, "想一想".to_owned()), (false, "答案".to_owned())]
+        );
+    }
+}
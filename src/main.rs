@@ -1,14 +1,50 @@
 use actix::{Actor, Recipient};
 use clap::{Arg, ArgAction, Command};
-use serde::Deserialize;
-use std::{collections::HashMap, fs::File, io::BufReader, net::Ipv4Addr, path::Path};
+use futures::future::Either;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use serde::de::DeserializeOwned;
+use std::{
+    collections::HashMap,
+    io::{IsTerminal, Write},
+    net::Ipv4Addr,
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 
-use actix_web::{head, middleware::Logger, App, HttpServer, Result};
+use actix_multipart::form::MultipartFormConfig;
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceFactory, ServiceRequest, ServiceResponse},
+    get, head,
+    http::header::{Accept, Header},
+    middleware::Logger,
+    App, HttpRequest, HttpResponse, HttpServer, Result,
+};
 use llmserver_rs::{
-    asr::simple::SimpleASRConfig, huggingface::{check_model_exists, create_config_file, determine_model_type, ModelType},
-    llm::simple::SimpleLLMConfig, AIModel, ProcessAudio, ProcessMessages, ShutdownMessages,
+    asr::simple::{SimpleASR, SimpleASRConfig},
+    error::{set_error_log_format, ErrorLogFormat},
+    huggingface::{check_model_exists, create_config_file, determine_model_type, ensure_cache_space, ModelType},
+    idempotency::IdempotencyCache,
+    llm::simple::{SimpleLLMConfig, SimpleRkLLM},
+    metrics::Metrics,
+    models::{LoadFailures, ModelDetails},
+    npu::set_npu_concurrency,
+    slo::Slo,
+    watchdog::Notifier,
+    AIModel,
+    LlmInstance,
+    Ping,
+    ProcessAudio,
+    ProcessMessages,
+    Restarter,
+    ServerConfig,
+    ShutdownMessages,
 };
-use utoipa_actix_web::{scope, AppExt};
+use utoipa_actix_web::{scope, service_config::ServiceConfig, AppExt};
 use utoipa_swagger_ui::SwaggerUi;
 
 /// Get health of the API.
@@ -22,8 +58,851 @@ async fn health() -> &'static str {
     ""
 }
 
+/// What `/` shows, `None` in app data when disabled with `--no-root-page`.
+#[derive(Clone)]
+struct RootPage {
+    message: Option<String>,
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Basic server info, HTML for browsers and JSON otherwise.
+#[utoipa::path(
+    responses(
+        (status = OK, description = "Success", content_type = "application/json"),
+        (status = NOT_FOUND, description = "Disabled with --no-root-page")
+    )
+)]
+#[get("/")]
+async fn root(
+    req: HttpRequest,
+    page: actix_web::web::Data<Option<RootPage>>,
+    server_config: actix_web::web::Data<ServerConfig>,
+) -> HttpResponse {
+    let Some(page) = page.as_ref() else {
+        return HttpResponse::NotFound().finish();
+    };
+    let models = format!("{}/models", server_config.api_prefix);
+    let wants_html = Accept::parse(&req)
+        .ok()
+        .and_then(|accept| accept.ranked().into_iter().next())
+        .is_some_and(|mime| mime.essence_str() == "text/html");
+    if wants_html {
+        let message = page
+            .message
+            .as_deref()
+            .map(|message| format!("<p>{}</p>", escape_html(message)))
+            .unwrap_or_default();
+        HttpResponse::Ok().content_type("text/html; charset=utf-8").body(format!(
+            "<!DOCTYPE html><html><head><title>llmserver-rs</title></head><body><h1>llmserver-rs {}</h1>{}<ul><li><a href=\"/swagger-ui/\">API documentation</a></li><li><a href=\"{}\">Models</a></li></ul></body></html>",
+            env!("CARGO_PKG_VERSION"),
+            message,
+            escape_html(&models),
+        ))
+    } else {
+        HttpResponse::Ok().json(serde_json::json!({
+            "name": "llmserver-rs",
+            "version": env!("CARGO_PKG_VERSION"),
+            "message": page.message,
+            "docs": "/swagger-ui/",
+            "models": models,
+        }))
+    }
+}
+
+/// Reads a model config, also returning the file's text so a reload can tell
+/// whether it changed.
+fn read_config<T: DeserializeOwned>(path: &str) -> Result<(T, String), Box<dyn std::error::Error>> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("Config {} not readable: {}", path, e))?;
+    Ok((serde_json::from_str(&text)?, text))
+}
+
+fn read_llm_config(path: &str) -> Result<(SimpleLLMConfig, String), Box<dyn std::error::Error>> {
+    let (config, text) = read_config::<SimpleLLMConfig>(path)?;
+    config.validate()?;
+    Ok((config, text))
+}
+
+/// Remembers which config first claimed a model name. A second config with the
+/// same name but another model path would silently share its recipient pool,
+/// so that is an error unless merging was explicitly allowed.
+fn register_model_name(
+    sources: &mut HashMap<String, (String, String)>,
+    name: &str,
+    model_path: &str,
+    config_file: &str,
+    allow_merge: bool,
+) -> Result<(), String> {
+    match sources.get(name) {
+        Some((path, first_config)) if path != model_path => {
+            eprintln!(
+                "Model name {} is declared by {} ({}) and {} ({})",
+                name, first_config, path, config_file, model_path
+            );
+            if allow_merge {
+                println!("Merging instances of both configs under {}", name);
+                Ok(())
+            } else {
+                Err(format!(
+                    "Duplicate model name {} in {} and {}, pass --merge-duplicate-models to serve them as one",
+                    name, first_config, config_file
+                ))
+            }
+        }
+        Some(_) => Ok(()),
+        None => {
+            sources.insert(name.to_owned(), (model_path.to_owned(), config_file.to_owned()));
+            Ok(())
+        }
+    }
+}
+
+fn api_routes(config: &mut ServiceConfig) {
+    config
+        .service(llmserver_rs::chat::chat_completions)
+        .service(llmserver_rs::audio::audio_transcriptions)
+        .service(llmserver_rs::models::list_models)
+        .service(llmserver_rs::models::retrieve_model);
+}
+
+/// Every route but the Swagger UI, the OpenAI ones under `api_prefix`.
+fn routes(config: &mut ServiceConfig, api_prefix: &str) {
+    // An empty scope would match every path ahead of the routes after it
+    if api_prefix.is_empty() {
+        api_routes(config);
+    } else {
+        config.service(scope::scope(api_prefix).configure(api_routes));
+    }
+    config
+        .service(health)
+        .service(root)
+        .service(llmserver_rs::metrics::metrics);
+}
+
+/// Turns `api/v1/` into `/api/v1`, keeping empty (or `/`) as the root.
+fn normalize_prefix(prefix: &str) -> String {
+    let trimmed = prefix.trim_matches('/');
+    if trimmed.is_empty() {
+        String::new()
+    } else {
+        format!("/{}", trimmed)
+    }
+}
+
+const CONFIG_DIR: &str = "assets/config";
+/// Quiet period that ends a burst of config file events.
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(500);
+
+fn config_file_for(model_id: &str) -> String {
+    let parts: Vec<&str> = model_id.split('/').collect();
+    let model_name = if parts.len() == 2 { parts[1] } else { model_id };
+    format!("{}/{}.json", CONFIG_DIR, model_name.to_lowercase().replace('-', "_"))
+}
+
+/// The `.json` files in the config directory, sorted.
+fn config_dir_files() -> Vec<String> {
+    let Ok(entries) = std::fs::read_dir(CONFIG_DIR) else {
+        return Vec::new();
+    };
+    let mut files = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .map(|path| path.to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+    files.sort();
+    files
+}
+
+/// The command line models plus every config added to the config directory
+/// since startup. Configs that were there before, such as the shipped ones,
+/// are only served when their model is on the command line.
+fn watched_models(cli_models: &[(String, String)], startup_configs: &[String]) -> Vec<(String, String)> {
+    let mut models = cli_models.to_vec();
+    let added = config_dir_files()
+        .into_iter()
+        .filter(|path| !startup_configs.contains(path))
+        .filter(|path| !cli_models.iter().any(|(_, config)| Path::new(config) == Path::new(path)))
+        .filter_map(|path| match read_config::<serde_json::Value>(&path) {
+            Ok((config, _)) => match config.get("modle_path").and_then(|p| p.as_str()) {
+                Some(model_id) => Some((model_id.to_owned(), path)),
+                None => {
+                    eprintln!("Ignoring {}: no modle_path", path);
+                    None
+                }
+            },
+            Err(e) => {
+                eprintln!("Ignoring malformed config {}: {}", path, e);
+                None
+            }
+        });
+    models.extend(added);
+    models
+}
+
+/// Watches `dir` and sends on `reload_tx` once per burst of config changes.
+fn watch_config_dir(dir: &Path, reload_tx: tokio::sync::mpsc::Sender<String>) -> notify::Result<RecommendedWatcher> {
+    let (event_tx, event_rx) = std::sync::mpsc::channel::<notify::Result<notify::Event>>();
+    let mut watcher = notify::recommended_watcher(event_tx)?;
+    watcher.watch(dir, RecursiveMode::NonRecursive)?;
+    std::thread::spawn(move || {
+        while let Ok(event) = event_rx.recv() {
+            let is_config_change = event.is_ok_and(|event| {
+                !event.kind.is_access()
+                    && event.paths.iter().any(|path| path.extension().is_some_and(|ext| ext == "json"))
+            });
+            if !is_config_change {
+                continue;
+            }
+            while event_rx.recv_timeout(WATCH_DEBOUNCE).is_ok() {}
+            // A full channel already has a reload pending
+            if reload_tx.try_send("Config change detected".to_owned()).is_err() && reload_tx.is_closed() {
+                break;
+            }
+        }
+    });
+    Ok(watcher)
+}
+
+struct LoadSettings {
+    num_instances: usize,
+    skip_bad_configs: bool,
+    merge_duplicate_models: bool,
+    load_concurrency: usize,
+    /// Abort instead of answering 503 for models that failed to load.
+    fail_on_load_error: bool,
+    fallback_cache_dir: Option<std::path::PathBuf>,
+    /// `--model-type` overrides by model id.
+    model_types: HashMap<String, ModelType>,
+}
+
+/// The `--model-type` override, else the name based guess, else asks on a
+/// terminal. Without a terminal an ambiguous model is an error.
+fn resolve_model_type(model_id: &str, overrides: &HashMap<String, ModelType>) -> Result<ModelType, String> {
+    if let Some(model_type) = overrides.get(model_id) {
+        return Ok(*model_type);
+    }
+    if let Some(model_type) = determine_model_type(model_id) {
+        return Ok(model_type);
+    }
+    let ambiguous = format!("Cannot tell whether {} is an LLM or an ASR model", model_id);
+    if !std::io::stdin().is_terminal() {
+        return Err(format!("{}, pass --model-type {}=llm or --model-type {}=asr", ambiguous, model_id, model_id));
+    }
+    loop {
+        print!("{}. Type llm or asr: ", ambiguous);
+        std::io::stdout().flush().map_err(|e| e.to_string())?;
+        let mut answer = String::new();
+        if std::io::stdin().read_line(&mut answer).map_err(|e| e.to_string())? == 0 {
+            return Err(format!("{}, no answer given", ambiguous));
+        }
+        match parse_model_type(answer.trim()) {
+            Ok(model_type) => {
+                println!("Loading {} as {:?}, pass --model-type to skip this question", model_id, model_type);
+                return Ok(model_type);
+            }
+            Err(e) => println!("{}", e),
+        }
+    }
+}
+
+fn parse_model_type(value: &str) -> Result<ModelType, String> {
+    match value.to_lowercase().as_str() {
+        "llm" => Ok(ModelType::LLM),
+        "asr" => Ok(ModelType::ASR),
+        _ => Err(format!("expected llm or asr, got {}", value)),
+    }
+}
+
+/// Parses `--model-type model_id=llm|asr`.
+fn parse_model_type_override(value: &str) -> Result<(String, ModelType), String> {
+    let Some((model_id, model_type)) = value.split_once('=') else {
+        return Err(format!("expected model_id=llm or model_id=asr, got {}", value));
+    };
+    Ok((model_id.to_owned(), parse_model_type(model_type)?))
+}
+
+/// Everything the HTTP workers need to reach the loaded models.
+#[derive(Default, Clone)]
+struct Models {
+    llm_recipients: HashMap<String, Vec<LlmInstance>>,
+    audio_recipients: HashMap<String, Vec<Recipient<ProcessAudio>>>,
+    llm_configs: HashMap<String, SimpleLLMConfig>,
+    model_registry: HashMap<String, ModelDetails>,
+    load_failures: LoadFailures,
+    shutdown_recipients: Vec<Recipient<ShutdownMessages>>,
+    /// Every instance with its model name, for the systemd watchdog.
+    ping_recipients: Vec<(String, Recipient<Ping>)>,
+}
+
+impl Models {
+    /// Combines the models loaded from every config into what is served.
+    fn merge<'a>(parts: impl IntoIterator<Item = &'a Models>) -> Models {
+        let mut models = Models::default();
+        let mut failures = HashMap::new();
+        for part in parts {
+            for (name, instances) in &part.llm_recipients {
+                models.llm_recipients.entry(name.clone()).or_default().extend(instances.iter().cloned());
+            }
+            for (name, instances) in &part.audio_recipients {
+                models.audio_recipients.entry(name.clone()).or_default().extend(instances.iter().cloned());
+            }
+            for (name, config) in &part.llm_configs {
+                // Configs merged under one name agree on what the handlers read
+                models.llm_configs.entry(name.clone()).or_insert_with(|| config.clone());
+            }
+            for (name, details) in &part.model_registry {
+                models
+                    .model_registry
+                    .entry(name.clone())
+                    .and_modify(|merged| merged.instances += details.instances)
+                    .or_insert_with(|| details.clone());
+            }
+            for (name, error) in &part.load_failures.0 {
+                failures.entry(name.clone()).or_insert_with(|| error.clone());
+            }
+            models.shutdown_recipients.extend(part.shutdown_recipients.iter().cloned());
+            models.ping_recipients.extend(part.ping_recipients.iter().cloned());
+        }
+        for (name, error) in failures {
+            // Another config serving the same name did load
+            if !models.model_registry.contains_key(&name) {
+                models.load_failures.0.insert(name, error);
+            }
+        }
+        models
+    }
+
+    fn is_empty(&self) -> bool {
+        self.llm_recipients.is_empty() && self.audio_recipients.is_empty()
+    }
+
+    /// Whether an instance answers pings with dead, or its actor is gone.
+    async fn has_dead_instance(&self) -> bool {
+        let pings = self.ping_recipients.iter().map(|(_, instance)| async move {
+            // A busy instance that does not answer in time is not dead
+            let ping = actix_web::rt::time::timeout(Duration::from_secs(5), instance.send(Ping)).await;
+            matches!(ping, Ok(Ok(false)) | Ok(Err(_)))
+        });
+        futures::future::join_all(pings).await.into_iter().any(|dead| dead)
+    }
+}
+
+/// A `(model id, config file)` pair whose config was read and checked.
+struct Prepared {
+    model_id: String,
+    config_file: String,
+    /// The config file's text, reloads restart a model only when it changed.
+    config_text: String,
+    /// `None` for a malformed config skipped with `--skip-bad-configs`.
+    job: Option<LoadJob>,
+}
+
+/// The models served from one `(model id, config file)` pair.
+#[derive(Clone)]
+struct Entry {
+    model_id: String,
+    config_file: String,
+    config_text: String,
+    models: Models,
+}
+
+/// Reads the config of every `(model id, config file)` pair, creating missing
+/// ones. Model names are checked across all of them.
+fn prepare_models(model_ids: &[(String, String)], settings: &LoadSettings) -> Result<Vec<Prepared>, Box<dyn std::error::Error>> {
+    let mut prepared = Vec::new();
+    let mut skipped_configs = Vec::new();
+    let mut model_sources = HashMap::new();
+    let mut llm_configs = HashMap::<String, SimpleLLMConfig>::new();
+
+    for (model_id, config_file_name) in model_ids {
+        // Determine model type
+        let model_type = resolve_model_type(model_id, &settings.model_types)?;
+
+        // Create config file if it doesn't exist
+        if !Path::new(config_file_name).exists() {
+            if !check_model_exists(model_id) {
+                return Err(format!("Model {} does not exist or is not accessible on Hugging Face", model_id).into());
+            }
+            println!("Creating config file for model: {}", model_id);
+            let config_path = create_config_file(model_id, model_type)?;
+            println!("Created config file: {}", config_path);
+        }
+
+        let read = match model_type {
+            ModelType::LLM => read_llm_config(config_file_name).map(|(config, text)| (LoadJob::Llm(config), text)),
+            ModelType::ASR => {
+                read_config::<SimpleASRConfig>(config_file_name).map(|(config, text)| (LoadJob::Asr(config), text))
+            }
+        };
+        let (job, config_text) = match read {
+            Ok(read) => read,
+            Err(e) if settings.skip_bad_configs => {
+                eprintln!("Skipping malformed config {}: {}", config_file_name, e);
+                skipped_configs.push(config_file_name.clone());
+                prepared.push(Prepared {
+                    model_id: model_id.clone(),
+                    config_file: config_file_name.clone(),
+                    config_text: String::new(),
+                    job: None,
+                });
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        let (name, path) = match &job {
+            LoadJob::Llm(config) => (&config.modle_name, &config.modle_path),
+            LoadJob::Asr(config) => (&config.modle_name, &config.modle_path),
+        };
+        register_model_name(&mut model_sources, name, path, config_file_name, settings.merge_duplicate_models)?;
+        if let LoadJob::Llm(config) = &job {
+            // The chat handler looks these limits up by name, whichever
+            // instance ends up serving the request
+            if let Some(first) = llm_configs.get(&config.modle_name) {
+                let conflicts = first.request_limit_conflicts(config);
+                if !conflicts.is_empty() {
+                    return Err(format!(
+                        "{} serves {} with different {} than an earlier config, merged configs must agree on them",
+                        config_file_name,
+                        config.modle_name,
+                        conflicts.join(", ")
+                    )
+                    .into());
+                }
+            }
+            llm_configs.entry(config.modle_name.clone()).or_insert_with(|| config.clone());
+        }
+        prepared.push(Prepared {
+            model_id: model_id.clone(),
+            config_file: config_file_name.clone(),
+            config_text,
+            job: Some(job),
+        });
+    }
+
+    if !skipped_configs.is_empty() {
+        println!(
+            "Skipped {} malformed config(s): {}",
+            skipped_configs.len(),
+            skipped_configs.join(", ")
+        );
+    }
+    Ok(prepared)
+}
+
+/// Init results by index into the prepared configs.
+type LoadedInstances = Vec<(usize, Result<Loaded, String>)>;
+
+/// Downloads and initializes every instance of the prepared configs. This
+/// blocks for minutes, so reloads run it off the actix thread the running
+/// models answer on.
+fn load_instances(prepared: &mut [Prepared], settings: &LoadSettings) -> Result<LoadedInstances, String> {
+    let mut first_instances = Vec::new();
+    let mut other_instances = Vec::new();
+    for (entry, config) in prepared.iter_mut().enumerate() {
+        let Some(job) = &mut config.job else {
+            continue;
+        };
+        let model_id = &config.model_id;
+        if !check_model_exists(model_id) {
+            return Err(format!("Model {} does not exist or is not accessible on Hugging Face", model_id));
+        }
+        if let LoadJob::Llm(config) = job {
+            config.resolve_model_file()?;
+        }
+        // Only the files SimpleRkLLM downloads, ASR models fetch their whole repo
+        let files = match &*job {
+            LoadJob::Llm(config) => Some([config.model_file()?, "tokenizer_config.json".to_owned(), "tokenizer.json".to_owned()]),
+            LoadJob::Asr(_) => None,
+        };
+        let files = files.as_ref().map(|files| files.iter().map(String::as_str).collect::<Vec<_>>());
+        ensure_cache_space(model_id, files.as_deref(), settings.fallback_cache_dir.as_deref())?;
+
+        // The first instance downloads the model files, the others must not
+        // race it for the same cache entries
+        first_instances.push((entry, (model_id.clone(), job.clone())));
+        for _ in 1..settings.num_instances {
+            other_instances.push((entry, (model_id.clone(), job.clone())));
+        }
+    }
+
+    let (first_entries, first_jobs): (Vec<_>, Vec<_>) = first_instances.into_iter().unzip();
+    let (other_entries, other_jobs): (Vec<_>, Vec<_>) = other_instances.into_iter().unzip();
+    let mut loaded = load_concurrently(&first_jobs, settings.load_concurrency);
+    loaded.extend(load_concurrently(&other_jobs, settings.load_concurrency));
+    Ok(first_entries.into_iter().chain(other_entries).zip(loaded).collect())
+}
+
+/// Starts the loaded instances on the actix thread. Returns the models of
+/// every prepared config and the init errors, a failed instance is skipped.
+fn start_instances(prepared: &[Prepared], loaded: LoadedInstances) -> (Vec<Models>, Vec<String>) {
+    let mut models = prepared.iter().map(|_| Models::default()).collect::<Vec<_>>();
+    let mut errors = Vec::new();
+    for (config, models) in prepared.iter().zip(&mut models) {
+        if let Some(LoadJob::Llm(config)) = &config.job {
+            models.llm_configs.insert(config.modle_name.clone(), config.clone());
+        }
+    }
+    for (entry, model) in loaded {
+        let models = &mut models[entry];
+        let model = match model {
+            Ok(model) => model,
+            Err(e) => {
+                errors.push(format!("Failed to initialize model {}: {}", prepared[entry].model_id, e));
+                let name = prepared[entry].job.as_ref().map_or("", LoadJob::model_name);
+                // Later instances usually fail the same way, keep the first error
+                models.load_failures.0.entry(name.to_owned()).or_insert(e);
+                continue;
+            }
+        };
+        match model {
+            Loaded::Llm(llm) => {
+                let details = llm.model_details();
+                let model_name = details.model.id.clone();
+                models.model_registry.entry(model_name.clone()).or_insert(details).instances += 1;
+                let npu_cores = llm.npu_cores();
+                let queued = llm.queued();
+                let addr = llm.start();
+                models.ping_recipients.push((model_name.clone(), addr.clone().recipient::<Ping>()));
+                models.llm_recipients.entry(model_name).or_default().push(LlmInstance {
+                    recipient: addr.clone().recipient::<ProcessMessages>(),
+                    npu_cores,
+                    queued,
+                });
+                models.shutdown_recipients.push(addr.recipient::<ShutdownMessages>());
+            }
+            Loaded::Asr(asr) => {
+                let details = asr.model_details();
+                let model_name = details.model.id.clone();
+                models.model_registry.entry(model_name.clone()).or_insert(details).instances += 1;
+                let addr = asr.start();
+                models.ping_recipients.push((model_name.clone(), addr.clone().recipient::<Ping>()));
+                models
+                    .audio_recipients
+                    .entry(model_name)
+                    .or_default()
+                    .push(addr.clone().recipient::<ProcessAudio>());
+                models.shutdown_recipients.push(addr.recipient::<ShutdownMessages>());
+            }
+        }
+    }
+    for models in &models {
+        for model_name in models.load_failures.0.keys() {
+            if !models.model_registry.contains_key(model_name) {
+                eprintln!("No instance of {} loaded, requests for it get a 503", model_name);
+            }
+        }
+    }
+    (models, errors)
+}
+
+/// Loads every configured model at startup.
+fn load_models(model_ids: &[(String, String)], settings: &LoadSettings) -> Result<Vec<Entry>, Box<dyn std::error::Error>> {
+    let mut prepared = prepare_models(model_ids, settings)?;
+    let loaded = load_instances(&mut prepared, settings)?;
+    let (models, errors) = start_instances(&prepared, loaded);
+    if settings.fail_on_load_error {
+        if let Some(error) = errors.into_iter().next() {
+            return Err(error.into());
+        }
+    }
+    let entries = prepared
+        .into_iter()
+        .zip(models)
+        .map(|(prepared, models)| Entry {
+            model_id: prepared.model_id,
+            config_file: prepared.config_file,
+            config_text: prepared.config_text,
+            models,
+        })
+        .collect::<Vec<_>>();
+    if Models::merge(entries.iter().map(|entry| &entry.models)).is_empty() {
+        return Err("Failed to load any model".into());
+    }
+    Ok(entries)
+}
+
+/// What a reload does with one served config.
+enum Step {
+    Keep(usize),
+    Load(usize),
+}
+
+/// Loads the configs that changed or have a dead instance next to the
+/// running models. Returns the entries to serve from now on and the
+/// instances to shut down once the old server let its requests finish, or
+/// `None` when there is nothing to do. On error nothing was changed.
+async fn reload(
+    entries: &[Entry],
+    model_ids: &[(String, String)],
+    settings: &Arc<LoadSettings>,
+) -> Result<Option<(Vec<Entry>, Vec<Recipient<ShutdownMessages>>)>, String> {
+    let prepared = prepare_models(model_ids, settings).map_err(|e| e.to_string())?;
+    let running = |prepared: &Prepared| {
+        entries
+            .iter()
+            .position(|entry| entry.model_id == prepared.model_id && entry.config_file == prepared.config_file)
+    };
+    let mut steps = Vec::new();
+    let mut to_load = Vec::new();
+    for prepared in prepared {
+        let keep = match running(&prepared) {
+            // A half-written config keeps what it served before
+            Some(old) if prepared.job.is_none() => {
+                eprintln!("Keeping the running instances of {}", prepared.config_file);
+                Some(old)
+            }
+            Some(old) if entries[old].config_text == prepared.config_text => {
+                if entries[old].models.has_dead_instance().await {
+                    println!("Restarting {}, an instance of it is dead", prepared.config_file);
+                    None
+                } else {
+                    Some(old)
+                }
+            }
+            _ => None,
+        };
+        match keep {
+            Some(old) => steps.push(Step::Keep(old)),
+            None => {
+                steps.push(Step::Load(to_load.len()));
+                to_load.push(prepared);
+            }
+        }
+    }
+    let unchanged = steps.len() == entries.len()
+        && steps.iter().enumerate().all(|(i, step)| matches!(step, Step::Keep(old) if *old == i));
+    if unchanged {
+        return Ok(None);
+    }
+
+    let load_settings = settings.clone();
+    let (to_load, loaded) = actix_web::rt::task::spawn_blocking(move || {
+        let mut to_load = to_load;
+        let loaded = load_instances(&mut to_load, &load_settings);
+        (to_load, loaded)
+    })
+    .await
+    .map_err(|e| e.to_string())?;
+    let (models, errors) = start_instances(&to_load, loaded?);
+    let started = models.iter().flat_map(|models| models.shutdown_recipients.clone()).collect::<Vec<_>>();
+    if settings.fail_on_load_error {
+        if let Some(error) = errors.into_iter().next() {
+            shut_down(started).await;
+            return Err(error);
+        }
+    }
+
+    let mut loaded = to_load.into_iter().zip(models).map(Some).collect::<Vec<_>>();
+    let mut kept = vec![false; entries.len()];
+    let mut next = Vec::new();
+    for step in steps {
+        match step {
+            Step::Keep(old) => {
+                kept[old] = true;
+                next.push(entries[old].clone());
+            }
+            Step::Load(i) => {
+                let (prepared, models) = loaded[i].take().expect("Every config is loaded once");
+                // A config that no longer loads keeps serving its old instances
+                if let Some(old) = running(&prepared).filter(|&old| models.is_empty() && !entries[old].models.is_empty()) {
+                    eprintln!("No instance of {} loaded, keeping the running ones", prepared.config_file);
+                    kept[old] = true;
+                    next.push(entries[old].clone());
+                    continue;
+                }
+                next.push(Entry {
+                    model_id: prepared.model_id,
+                    config_file: prepared.config_file,
+                    config_text: prepared.config_text,
+                    models,
+                });
+            }
+        }
+    }
+    if Models::merge(next.iter().map(|entry| &entry.models)).is_empty() {
+        shut_down(started).await;
+        return Err("No model would be left".to_owned());
+    }
+    let retired = entries
+        .iter()
+        .zip(kept)
+        .filter(|(_, kept)| !kept)
+        .flat_map(|(entry, _)| entry.models.shutdown_recipients.clone())
+        .collect();
+    Ok(Some((next, retired)))
+}
+
+async fn shut_down(instances: Vec<Recipient<ShutdownMessages>>) {
+    let shutdowns = instances.into_iter().map(|addr| async move {
+        let _ = addr.send(ShutdownMessages).await;
+    });
+    futures::future::join_all(shutdowns).await;
+}
+
+#[derive(Clone)]
+enum LoadJob {
+    Llm(SimpleLLMConfig),
+    Asr(SimpleASRConfig),
+}
+
+impl LoadJob {
+    fn model_name(&self) -> &str {
+        match self {
+            LoadJob::Llm(config) => &config.modle_name,
+            LoadJob::Asr(config) => &config.modle_name,
+        }
+    }
+}
+
+enum Loaded {
+    Llm(Box<SimpleRkLLM>),
+    Asr(Box<SimpleASR>),
+}
+
+/// The init error is returned as text, it ends up in log lines and 503 bodies.
+fn load(model_id: &str, job: &LoadJob) -> Result<Loaded, String> {
+    match job {
+        LoadJob::Llm(config) => match SimpleRkLLM::init(config) {
+            Ok(llm) => Ok(Loaded::Llm(Box::new(llm))),
+            Err(e) => {
+                eprintln!("Failed to initialize LLM model {}: {}", model_id, e);
+                Err(e.to_string())
+            }
+        },
+        LoadJob::Asr(config) => match SimpleASR::init(config) {
+            Ok(asr) => Ok(Loaded::Asr(Box::new(asr))),
+            Err(e) => {
+                eprintln!("Failed to initialize ASR model {}: {}", model_id, e);
+                Err(e.to_string())
+            }
+        },
+    }
+}
+
+/// Runs the jobs on at most `concurrency` threads, results stay in job order.
+/// Actors are started afterwards because they need the actix runtime thread.
+fn load_concurrently(jobs: &[(String, LoadJob)], concurrency: usize) -> Vec<Result<Loaded, String>> {
+    let next = AtomicUsize::new(0);
+    let results = jobs.iter().map(|_| Mutex::new(None)).collect::<Vec<_>>();
+    std::thread::scope(|s| {
+        for _ in 0..concurrency.min(jobs.len()) {
+            s.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some((model_id, job)) = jobs.get(i) else {
+                    break;
+                };
+                *results[i].lock().unwrap() = Some(load(model_id, job));
+            });
+        }
+    });
+    results
+        .into_iter()
+        .map(|result| result.into_inner().unwrap().expect("Every job is loaded"))
+        .collect()
+}
+
+fn parse_percentile(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(percentile) if percentile > 0.0 && percentile <= 100.0 => Ok(percentile),
+        _ => Err(format!("expected a percentile in (0, 100], got {}", value)),
+    }
+}
+
+/// Parses `--field-alias alias=field`.
+fn parse_field_alias(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((alias, field)) if !alias.is_empty() && !field.is_empty() => {
+            Ok((alias.to_owned(), field.to_owned()))
+        }
+        _ => Err(format!("expected alias=field, got {}", value)),
+    }
+}
+
+/// Flags for the OTLP exporter, only present when built with the `otlp` feature.
+fn otlp_args() -> Vec<Arg> {
+    #[cfg(feature = "otlp")]
+    return vec![
+        Arg::new("otlp_endpoint")
+            .long("otlp-endpoint")
+            .help("Also push metrics over OTLP/HTTP, e.g. http://collector:4318/v1/metrics.")
+            .action(ArgAction::Set),
+        Arg::new("otlp_interval")
+            .long("otlp-interval")
+            .help("Seconds between OTLP metric exports.")
+            .action(ArgAction::Set)
+            .value_parser(clap::value_parser!(u64).range(1..))
+            .default_value("60"),
+    ];
+    #[cfg(not(feature = "otlp"))]
+    Vec::new()
+}
+
+/// What every HTTP server main starts shares, whichever models it serves.
+#[derive(Clone)]
+struct AppState {
+    server_config: ServerConfig,
+    idempotency: actix_web::web::Data<IdempotencyCache>,
+    metrics: actix_web::web::Data<Metrics>,
+    root_page: Option<RootPage>,
+    restarter: Restarter,
+}
+
+/// One HTTP worker's app. Every worker gets the same `state`, so the shared
+/// `app_data` there is one instance across workers.
+fn app(
+    models: &Models,
+    state: &AppState,
+) -> App<
+    impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<impl MessageBody>,
+        Error = actix_web::Error,
+        InitError = (),
+    >,
+> {
+    let api_prefix = state.server_config.api_prefix.clone();
+    // utoipa-actix-web prepends the scope to every documented path, so the
+    // OpenAPI document follows the prefix without a separate servers entry
+    let (app, api) = App::new()
+        .app_data(actix_web::web::Data::new(models.llm_recipients.clone()))
+        .app_data(actix_web::web::Data::new(models.audio_recipients.clone()))
+        .app_data(actix_web::web::Data::new(models.llm_configs.clone()))
+        .app_data(actix_web::web::Data::new(state.server_config.clone()))
+        .app_data(actix_web::web::Data::new(models.model_registry.clone()))
+        .app_data(actix_web::web::Data::new(models.load_failures.clone()))
+        .app_data(state.idempotency.clone())
+        .app_data(state.metrics.clone())
+        .app_data(actix_web::web::Data::new(state.root_page.clone()))
+        .app_data(actix_web::web::Data::new(state.restarter.clone()))
+        .app_data(MultipartFormConfig::default().error_handler(llmserver_rs::audio::multipart_error))
+        .into_utoipa_app()
+        .map(|app| app.wrap(Logger::default()))
+        .configure(|config| routes(config, &api_prefix))
+        .split_for_parts();
+
+    app.service(SwaggerUi::new("/swagger-ui/{_:.*}").url("/api-docs/openapi.json", api))
+}
+
+/// Starts serving `models` on `listener`. Reloads start a new server on the
+/// same socket before the old one stops, so connections are never refused.
+fn serve(models: &Models, state: &AppState, listener: &std::net::TcpListener) -> std::io::Result<actix_web::dev::Server> {
+    let models = models.clone();
+    let state = state.clone();
+    let server = HttpServer::new(move || app(&models, &state))
+        .listen(listener.try_clone()?)?
+        .run();
+    Ok(server)
+}
+
 #[actix_web::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut started = Instant::now();
     const VERSION: &str = env!("CARGO_PKG_VERSION");
     std::env::set_var("RUST_LOG", "info");
     env_logger::init();
@@ -32,134 +911,436 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .about("Hugging Face model server")
         .version(VERSION)
         .arg_required_else_help(true)
-        .arg(Arg::new("model_name"))
+        .arg(Arg::new("model_name").num_args(1..).required(true))
         .arg(
             Arg::new("instances")
                 .short('i')
                 .help("How many model instances do you want to create.")
                 .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(usize))
                 .num_args(1),
         )
+        .arg(
+            Arg::new("skip_bad_configs")
+                .long("skip-bad-configs")
+                .help("Log and skip models whose config file is malformed instead of aborting.")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("timeout")
+                .long("timeout")
+                .help("Default generation timeout in seconds, model configs and requests may override it.")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .default_value("300"),
+        )
+        .arg(
+            Arg::new("api_prefix")
+                .long("api-prefix")
+                .help("Path prefix for the API routes, e.g. /api/v1. Pass an empty string to serve them at the root.")
+                .action(ArgAction::Set)
+                .default_value("/v1"),
+        )
+        .arg(
+            Arg::new("merge_duplicate_models")
+                .long("merge-duplicate-models")
+                .help("Allow configs with the same model name but different model paths to share one name.")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("idempotency_ttl")
+                .long("idempotency-ttl")
+                .help("Seconds to replay the response for a repeated Idempotency-Key, 0 disables it.")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(u64))
+                .default_value("0"),
+        )
+        .arg(
+            Arg::new("load_concurrency")
+                .long("load-concurrency")
+                .help("How many model instances to load at the same time, each one holds its model in memory while loading.")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(usize))
+                .default_value("1"),
+        )
+        .arg(
+            Arg::new("debug_replay_stream")
+                .long("debug-replay-stream")
+                .help("Test aid: allow requests to set replay_interval_ms and get a pre-generated response replayed as a stream.")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("watch")
+                .long("watch")
+                .help("Reload changed models when a file in assets/config changes, configs added there after startup are served too.")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("max_streams")
+                .long("max-streams")
+                .help("Most streaming responses open at once, further streaming requests get a 503. Unlimited by default.")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("npu_concurrency")
+                .long("npu-concurrency")
+                .help("Most LLM generations and ASR transcriptions running on the NPU at once, across all models. Unlimited by default.")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("slo_target_ms")
+                .long("slo-target-ms")
+                .help("Response time target in milliseconds. While the recent --slo-percentile is above it, requests with \"priority\": \"low\" get a 503. Off by default.")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(u64)),
+        )
+        .arg(
+            Arg::new("slo_percentile")
+                .long("slo-percentile")
+                .help("Percentile of recent response times held against --slo-target-ms.")
+                .action(ArgAction::Set)
+                .default_value("99")
+                .value_parser(parse_percentile),
+        )
+        .arg(
+            Arg::new("slo_window_secs")
+                .long("slo-window-secs")
+                .help("How many seconds of response times --slo-target-ms looks at.")
+                .action(ArgAction::Set)
+                .default_value("60")
+                .value_parser(clap::value_parser!(u64)),
+        )
+        .arg(
+            Arg::new("no_root_page")
+                .long("no-root-page")
+                .help("Answer / with 404 instead of basic server info.")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("root_message")
+                .long("root-message")
+                .help("Extra text shown on the / page, e.g. who runs this server.")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("error_log_format")
+                .long("error-log-format")
+                .help("How error responses are logged to stderr.")
+                .action(ArgAction::Set)
+                .value_parser(["text", "json", "off"])
+                .default_value("text"),
+        )
+        .arg(
+            Arg::new("gzip_streams")
+                .long("gzip-streams")
+                .help("Gzip streaming responses for clients sending Accept-Encoding: gzip, flushed after every chunk.")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("max_sse_chunk_bytes")
+                .long("max-sse-chunk-bytes")
+                .help("Split streamed content longer than this many bytes across several SSE frames.")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(usize))
+                .default_value("16384"),
+        )
+        .arg(
+            Arg::new("restart_dead_instances")
+                .long("restart-dead-instances")
+                .help("Reload every model when a request finds a model instance that is no longer running.")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("fail_on_load_error")
+                .long("fail-on-load-error")
+                .help("Exit when a model fails to initialize instead of serving the others and answering 503 for it.")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("model_type")
+                .long("model-type")
+                .help("Type of a model its name does not reveal, e.g. owner/name=llm or owner/name=asr. Repeatable.")
+                .action(ArgAction::Append)
+                .value_parser(parse_model_type_override),
+        )
+        .arg(
+            Arg::new("fallback_cache_dir")
+                .long("fallback-cache-dir")
+                .help("Download models that do not fit in the Hugging Face cache to this directory instead.")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("field_alias")
+                .long("field-alias")
+                .help("Accept a request body field under another name, e.g. maxTokens=max_tokens. Repeatable.")
+                .action(ArgAction::Append)
+                .value_parser(parse_field_alias),
+        )
+        .args(otlp_args())
         .get_matches();
 
+    set_error_log_format(match matches.get_one::<String>("error_log_format").unwrap().as_str() {
+        "json" => ErrorLogFormat::Json,
+        "off" => ErrorLogFormat::Off,
+        _ => ErrorLogFormat::Text,
+    });
+    if let Some(&limit) = matches.get_one::<usize>("npu_concurrency") {
+        set_npu_concurrency(limit.max(1));
+    }
+
     // Initialize model
     let mut num_instances = 1;
 
     if let Some(value) = matches.get_one::<usize>("instances") {
         num_instances = *value;
     }
-    let model_id = matches.get_one::<String>("model_name").unwrap();
+    let settings = Arc::new(LoadSettings {
+        num_instances,
+        skip_bad_configs: matches.get_flag("skip_bad_configs"),
+        merge_duplicate_models: matches.get_flag("merge_duplicate_models"),
+        load_concurrency: (*matches.get_one::<usize>("load_concurrency").unwrap()).max(1),
+        fail_on_load_error: matches.get_flag("fail_on_load_error"),
+        fallback_cache_dir: matches.get_one::<String>("fallback_cache_dir").map(Into::into),
+        model_types: matches
+            .get_many::<(String, ModelType)>("model_type")
+            .unwrap_or_default()
+            .cloned()
+            .collect(),
+    });
+    let server_config = ServerConfig {
+        default_timeout: Duration::from_secs(*matches.get_one::<u64>("timeout").unwrap()),
+        api_prefix: normalize_prefix(matches.get_one::<String>("api_prefix").unwrap()),
+        debug_replay: matches.get_flag("debug_replay_stream"),
+        max_streams: matches.get_one::<usize>("max_streams").copied(),
+        max_sse_chunk_bytes: (*matches.get_one::<usize>("max_sse_chunk_bytes").unwrap()).max(1),
+        gzip_streams: matches.get_flag("gzip_streams"),
+        field_aliases: matches
+            .get_many::<(String, String)>("field_alias")
+            .unwrap_or_default()
+            .cloned()
+            .collect(),
+    };
+    // One instance for every worker and reload, so counts are server wide
+    let slo = matches.get_one::<u64>("slo_target_ms").map(|&target| {
+        Slo::new(
+            Duration::from_millis(target),
+            *matches.get_one::<f64>("slo_percentile").unwrap(),
+            Duration::from_secs((*matches.get_one::<u64>("slo_window_secs").unwrap()).max(1)),
+        )
+    });
+    if let Some(slo) = &slo {
+        println!("Shedding low priority requests while p{} is above {:?}", slo.percentile(), slo.target());
+    }
+    let metrics = actix_web::web::Data::new(Metrics::with_slo(slo));
+    #[cfg(feature = "otlp")]
+    let otlp = match matches.get_one::<String>("otlp_endpoint") {
+        Some(endpoint) => Some(llmserver_rs::otlp::OtlpExporter::start(
+            metrics.clone().into_inner(),
+            endpoint,
+            Duration::from_secs(*matches.get_one::<u64>("otlp_interval").unwrap()),
+        )?),
+        None => None,
+    };
+    let root_page = (!matches.get_flag("no_root_page")).then(|| RootPage {
+        message: matches.get_one::<String>("root_message").cloned(),
+    });
+
+    // Created outside the worker factory so every worker sees the same entries
+    let idempotency = actix_web::web::Data::new(IdempotencyCache::new(Duration::from_secs(
+        *matches.get_one::<u64>("idempotency_ttl").unwrap(),
+    )));
+
+    let cli_models = matches
+        .get_many::<String>("model_name")
+        .unwrap()
+        .map(|model_id| (model_id.clone(), config_file_for(model_id)))
+        .collect::<Vec<_>>();
+    // Config changes and dead instances both reload models. Keeping a sender
+    // here means the channel never closes, so waiting on it is safe even when
+    // no reload source is enabled.
+    let (reload_tx, mut reload_rx) = tokio::sync::mpsc::channel::<String>(1);
+    let restarter = if matches.get_flag("restart_dead_instances") {
+        Restarter::new(reload_tx.clone())
+    } else {
+        Restarter::default()
+    };
+    // The watcher must stay alive for as long as we want events
+    let (watch, startup_configs) = if matches.get_flag("watch") {
+        std::fs::create_dir_all(CONFIG_DIR)?;
+        let startup_configs = config_dir_files();
+        (Some(watch_config_dir(Path::new(CONFIG_DIR), reload_tx.clone())?), startup_configs)
+    } else {
+        (None, Vec::new())
+    };
+    let model_ids = || match watch {
+        Some(_) => watched_models(&cli_models, &startup_configs),
+        None => cli_models.clone(),
+    };
 
-    // Check if model exists on Hugging Face
-    if !check_model_exists(model_id) {
-        panic!("Model {} does not exist or is not accessible on Hugging Face", model_id);
+    let notifier = match Notifier::from_env() {
+        Ok(notifier) => notifier.map(Arc::new),
+        Err(e) => {
+            eprintln!("Ignoring NOTIFY_SOCKET: {}", e);
+            None
+        }
+    };
+    if let Some(interval) = notifier.as_ref().and_then(|notifier| notifier.watchdog_interval()) {
+        println!("systemd watchdog enabled, pinging models every {:?}", interval);
     }
 
-    // Determine model type
-    let model_type = determine_model_type(model_id);
+    // Loading blocks the runtime the health checks run on, but is progress
+    let keep_alive = notifier.as_ref().map(|notifier| notifier.keep_alive());
+    let mut entries = load_models(&model_ids(), &settings)?;
+    drop(keep_alive);
+    let mut models = Models::merge(entries.iter().map(|entry| &entry.models));
+    println!("Loaded {} model instance(s) in {:.1?}", models.shutdown_recipients.len(), started.elapsed());
 
-    // Create config file if it doesn't exist
-    let parts: Vec<&str> = model_id.split('/').collect();
-    let model_name = if parts.len() == 2 { parts[1] } else { model_id };
-    
-    let config_file_name = format!("assets/config/{}.json", model_name.to_lowercase().replace('-', "_"));
-    if !Path::new(&config_file_name).exists() {
-        println!("Creating config file for model: {}", model_id);
-        let config_path = create_config_file(model_id, model_type)?;
-        println!("Created config file: {}", config_path);
-    }
-
-    // Text type LLM
-    let mut llm_recipients = HashMap::<String, Vec<Recipient<ProcessMessages>>>::new();
-    let mut audio_recipients = HashMap::<String, Vec<Recipient<ProcessAudio>>>::new();
-    let mut shutdown_recipients = Vec::new();
-
-    match model_type {
-        ModelType::LLM => {
-            // Initialize LLM model
-            for _ in 0..num_instances {
-                let file = File::open(&config_file_name)
-                    .expect(&format!("Config {} not found!", config_file_name));
-                let mut de = serde_json::Deserializer::from_reader(BufReader::new(file));
-                let config = SimpleLLMConfig::deserialize(&mut de)?;
-                let model_name = config.modle_name.clone();
-                
-                match llmserver_rs::llm::simple::SimpleRkLLM::init(&config) {
-                    Ok(llm) => {
-                        let addr = llm.start();
-                        if let Some(vec) = llm_recipients.get_mut(&model_name) {
-                            vec.push(addr.clone().recipient::<ProcessMessages>());
-                        } else {
-                            llm_recipients.insert(model_name, vec![addr.clone().recipient::<ProcessMessages>()]);
-                        }
-                        shutdown_recipients.push(addr.clone().recipient::<ShutdownMessages>());
-                    },
-                    Err(e) => {
-                        eprintln!("Failed to initialize LLM model {}: {}", model_id, e);
-                        panic!("Failed to initialize model");
-                    }
+    let state = AppState {
+        server_config,
+        idempotency,
+        metrics,
+        root_page,
+        restarter,
+    };
+    let listener = std::net::TcpListener::bind((Ipv4Addr::UNSPECIFIED, 8080))?;
+    let server = serve(&models, &state, &listener)?;
+    let mut handle = server.handle();
+    let mut server = actix_web::rt::spawn(server);
+    loop {
+        let watchdog = notifier.clone().map(|notifier| {
+            notifier.notify("READY=1");
+            actix_web::rt::spawn(llmserver_rs::watchdog::watch(notifier, models.ping_recipients.clone()))
+        });
+        let reason = match futures::future::select(&mut server, Box::pin(reload_rx.recv())).await {
+            Either::Left((result, _)) => {
+                if let Some(watchdog) = watchdog {
+                    watchdog.abort();
                 }
+                result??;
+                break;
             }
-        },
-        ModelType::ASR => {
-            // Initialize ASR model
-            for _ in 0..num_instances {
-                let file = File::open(&config_file_name)
-                    .expect(&format!("Config {} not found!", config_file_name));
-                let mut de = serde_json::Deserializer::from_reader(BufReader::new(file));
-                let config = SimpleASRConfig::deserialize(&mut de)?;
-                let model_name = config.modle_name.clone();
-                
-                match llmserver_rs::asr::simple::SimpleASR::init(&config) {
-                    Ok(asr) => {
-                        let addr = asr.start();
-                        if let Some(vec) = audio_recipients.get_mut(&model_name) {
-                            vec.push(addr.clone().recipient::<ProcessAudio>());
-                        } else {
-                            audio_recipients.insert(model_name, vec![addr.clone().recipient::<ProcessAudio>()]);
-                        }
-                        shutdown_recipients.push(addr.clone().recipient::<ShutdownMessages>());
-                    },
-                    Err(e) => {
-                        eprintln!("Failed to initialize ASR model {}: {}", model_id, e);
-                        panic!("Failed to initialize model");
-                    }
+            Either::Right((reason, _)) => reason.unwrap_or_default(),
+        };
+
+        if let Some(notifier) = &notifier {
+            notifier.notify("RELOADING=1");
+        }
+        println!("{}, reloading changed models", reason);
+        started = Instant::now();
+        // The old models keep serving while the changed ones load
+        let reloaded = match futures::future::select(&mut server, Box::pin(reload(&entries, &model_ids(), &settings))).await {
+            Either::Left((result, _)) => {
+                if let Some(watchdog) = watchdog {
+                    watchdog.abort();
                 }
+                result??;
+                break;
             }
+            Either::Right((reloaded, _)) => reloaded,
+        };
+        if let Some(watchdog) = watchdog {
+            watchdog.abort();
         }
+        let (next, retired) = match reloaded {
+            Ok(Some(reloaded)) => reloaded,
+            Ok(None) => {
+                println!("No model changed");
+                continue;
+            }
+            Err(e) => {
+                eprintln!("Reload failed, keeping the running models: {}", e);
+                continue;
+            }
+        };
+        entries = next;
+        models = Models::merge(entries.iter().map(|entry| &entry.models));
+        // Both servers accept on the same socket until the old one stopped
+        let next_server = serve(&models, &state, &listener)?;
+        let old_handle = std::mem::replace(&mut handle, next_server.handle());
+        let old_server = std::mem::replace(&mut server, actix_web::rt::spawn(next_server));
+        println!("Reloaded in {:.1?}, serving {} model instance(s)", started.elapsed(), models.shutdown_recipients.len());
+        actix_web::rt::spawn(async move {
+            old_handle.stop(true).await;
+            let _ = old_server.await;
+            // In-flight requests finished, nothing reaches these instances now
+            shut_down(retired).await;
+        });
     }
 
-    if audio_recipients.len() == 0 && llm_recipients.len() == 0 {
-        panic!("Failed to load any model");
+    if let Some(notifier) = &notifier {
+        notifier.notify("STOPPING=1");
+    }
+    shut_down(models.shutdown_recipients).await;
+    #[cfg(feature = "otlp")]
+    if let Some(otlp) = otlp {
+        otlp.shutdown();
     }
+    Ok(())
+}
 
-    HttpServer::new(move || {
-        let (app, api) = App::new()
-            .app_data(actix_web::web::Data::new(llm_recipients.clone()))
-            .app_data(actix_web::web::Data::new(audio_recipients.clone()))
-            .into_utoipa_app()
-            .map(|app| app.wrap(Logger::default()))
-            .service(
-                scope::scope("/v1")
-                    .service(llmserver_rs::chat::chat_completions)
-                    .service(llmserver_rs::audio::audio_transcriptions),
-            )
-            .service(health)
-            .split_for_parts();
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        http::StatusCode,
+        test::{call_and_read_body, call_service, init_service, TestRequest},
+    };
 
-        app.service(SwaggerUi::new("/swagger-ui/{_:.*}").url("/api-docs/openapi.json", api))
-    })
-    .bind((Ipv4Addr::UNSPECIFIED, 8080))?
-    .run()
-    .await?;
+    use super::*;
 
-    let shutdowns = shutdown_recipients.into_iter().map(|addr| async move {
-        let _ = addr.send(ShutdownMessages).await.unwrap();
-    });
+    #[actix_web::test]
+    async fn root_routes_answer_with_any_prefix() {
+        for prefix in ["", "/v1", "/api/v1"] {
+            let (app, _) = App::new()
+                .into_utoipa_app()
+                .configure(|config| routes(config, prefix))
+                .split_for_parts();
+            let app = init_service(app).await;
+            let request = TestRequest::default().method(actix_web::http::Method::HEAD).uri("/health");
+            let response = call_service(&app, request.to_request()).await;
+            assert_eq!(response.status(), StatusCode::OK, "prefix {:?}", prefix);
+        }
+    }
 
-    tokio::spawn(async {
-        futures::future::join_all(shutdowns).await;
-    })
-    .await?;
-    Ok(())
+    #[actix_web::test]
+    async fn metrics_are_summed_over_workers() {
+        let models = Models::default();
+        let state = AppState {
+            server_config: ServerConfig {
+                default_timeout: Duration::from_secs(30),
+                api_prefix: "/v1".to_owned(),
+                debug_replay: false,
+                max_streams: None,
+                max_sse_chunk_bytes: 1024,
+                field_aliases: HashMap::new(),
+                gzip_streams: false,
+            },
+            idempotency: actix_web::web::Data::new(IdempotencyCache::new(Duration::from_secs(60))),
+            metrics: actix_web::web::Data::new(Metrics::default()),
+            root_page: None,
+            restarter: Restarter::default(),
+        };
+        // What `serve` does for each of two HTTP workers
+        let workers = [init_service(app(&models, &state)).await, init_service(app(&models, &state)).await];
+        for worker in &workers {
+            let request = TestRequest::post().uri("/v1/chat/completions").set_json(serde_json::json!({
+                "model": "missing",
+                "messages": [{"role": "user", "content": "Hi"}],
+            }));
+            let response = call_service(worker, request.to_request()).await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        }
+        let body = call_and_read_body(&workers[0], TestRequest::get().uri("/metrics").to_request()).await;
+        let rendered = String::from_utf8(body.to_vec()).unwrap();
+        assert!(rendered.contains("llmserver_chat_requests_total 2\n"), "{}", rendered);
+    }
+
+    #[test]
+    fn prefixes() {
+        assert_eq!(normalize_prefix(""), "");
+        assert_eq!(normalize_prefix("/"), "");
+        assert_eq!(normalize_prefix("api/v1/"), "/api/v1");
+        assert_eq!(normalize_prefix("/v1"), "/v1");
+    }
 }
@@ -0,0 +1,319 @@
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use actix_web::{get, web, HttpResponse, Responder};
+
+use crate::{
+    llm::queue::{self, WaitStats},
+    npu,
+    slo::Slo,
+};
+
+/// Server wide counters. One instance is created before the HTTP server and
+/// handed to every worker through `app_data`, so `/metrics` shows the sum over
+/// all workers rather than whichever worker answered.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    open_streams: AtomicUsize,
+    rejected_streams: AtomicU64,
+    chat_requests: AtomicU64,
+    transcription_requests: AtomicU64,
+    generated_tokens: AtomicU64,
+    /// Chat template and tokenization time by model name.
+    templating: Mutex<HashMap<String, TemplateStats>>,
+    /// `--slo-target-ms`, shared so every worker sees the same window.
+    slo: Option<Slo>,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct TemplateStats {
+    prompts: u64,
+    seconds: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricKind {
+    Gauge,
+    Counter,
+}
+
+impl MetricKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MetricKind::Gauge => "gauge",
+            MetricKind::Counter => "counter",
+        }
+    }
+}
+
+/// One metric with the metadata every exporter needs and its current readings.
+#[derive(Debug, Clone)]
+pub struct MetricFamily {
+    pub name: &'static str,
+    pub kind: MetricKind,
+    pub help: &'static str,
+    pub samples: Vec<Sample>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Sample {
+    pub labels: Vec<(&'static str, String)>,
+    pub value: f64,
+}
+
+impl Sample {
+    fn unlabeled(value: f64) -> Self {
+        Sample {
+            labels: Vec::new(),
+            value,
+        }
+    }
+}
+
+/// Counts one open streaming response until dropped.
+#[derive(Debug)]
+pub struct StreamGuard(Arc<Metrics>);
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        self.0.open_streams.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    pub fn with_slo(slo: Option<Slo>) -> Self {
+        Metrics {
+            slo,
+            ..Default::default()
+        }
+    }
+
+    pub fn slo(&self) -> Option<&Slo> {
+        self.slo.as_ref()
+    }
+
+    /// Claims a stream slot, `None` when `limit` streams are already open.
+    pub fn open_stream(self: &Arc<Self>, limit: Option<usize>) -> Option<StreamGuard> {
+        let claimed = self
+            .open_streams
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |open| {
+                (limit.is_none_or(|limit| open < limit)).then_some(open + 1)
+            });
+        match claimed {
+            Ok(_) => Some(StreamGuard(self.clone())),
+            Err(_) => {
+                self.rejected_streams.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    pub fn count_chat_request(&self) {
+        self.chat_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count_transcription_request(&self) {
+        self.transcription_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts one piece of generated text, rkllm reports one per token.
+    pub fn count_token(&self) {
+        self.generated_tokens.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_template(&self, model: &str, elapsed: Duration) {
+        let mut templating = self.templating.lock().unwrap();
+        let stats = templating.entry(model.to_owned()).or_default();
+        stats.prompts += 1;
+        stats.seconds += elapsed.as_secs_f64();
+    }
+
+    /// Every metric, shared by the Prometheus and OTLP exporters.
+    pub fn families(&self) -> Vec<MetricFamily> {
+        let templating = self.templating.lock().unwrap().clone();
+        let mut models = templating.into_iter().collect::<Vec<_>>();
+        models.sort_by(|a, b| a.0.cmp(&b.0));
+        let by_model = |value: fn(&TemplateStats) -> f64| {
+            models
+                .iter()
+                .map(|(model, stats)| Sample {
+                    labels: vec![("model", model.clone())],
+                    value: value(stats),
+                })
+                .collect()
+        };
+        let mut families = vec![
+            MetricFamily {
+                name: "llmserver_open_streams",
+                kind: MetricKind::Gauge,
+                help: "Streaming responses currently open.",
+                samples: vec![Sample::unlabeled(self.open_streams.load(Ordering::Relaxed) as f64)],
+            },
+            MetricFamily {
+                name: "llmserver_rejected_streams_total",
+                kind: MetricKind::Counter,
+                help: "Streaming requests rejected by --max-streams.",
+                samples: vec![Sample::unlabeled(self.rejected_streams.load(Ordering::Relaxed) as f64)],
+            },
+            MetricFamily {
+                name: "llmserver_chat_requests_total",
+                kind: MetricKind::Counter,
+                help: "Chat completion requests received.",
+                samples: vec![Sample::unlabeled(self.chat_requests.load(Ordering::Relaxed) as f64)],
+            },
+            MetricFamily {
+                name: "llmserver_transcription_requests_total",
+                kind: MetricKind::Counter,
+                help: "Audio transcription requests received.",
+                samples: vec![Sample::unlabeled(self.transcription_requests.load(Ordering::Relaxed) as f64)],
+            },
+            MetricFamily {
+                name: "llmserver_generated_tokens_total",
+                kind: MetricKind::Counter,
+                help: "Tokens generated for chat completions.",
+                samples: vec![Sample::unlabeled(self.generated_tokens.load(Ordering::Relaxed) as f64)],
+            },
+            MetricFamily {
+                name: "llmserver_npu_active_jobs",
+                kind: MetricKind::Gauge,
+                help: "LLM generations and ASR transcriptions running on the NPU.",
+                samples: vec![Sample::unlabeled(npu::active() as f64)],
+            },
+            MetricFamily {
+                name: "llmserver_npu_waits_total",
+                kind: MetricKind::Counter,
+                help: "NPU jobs that waited for a slot under --npu-concurrency.",
+                samples: vec![Sample::unlabeled(npu::waits() as f64)],
+            },
+            MetricFamily {
+                name: "llmserver_npu_wait_seconds_total",
+                kind: MetricKind::Counter,
+                help: "Time NPU jobs spent waiting for a slot under --npu-concurrency.",
+                samples: vec![Sample::unlabeled(npu::wait_seconds())],
+            },
+            MetricFamily {
+                name: "llmserver_template_seconds_total",
+                kind: MetricKind::Counter,
+                help: "CPU time spent applying the chat template and tokenizing prompts.",
+                samples: by_model(|stats| stats.seconds),
+            },
+            MetricFamily {
+                name: "llmserver_templated_prompts_total",
+                kind: MetricKind::Counter,
+                help: "Prompts run through the chat template.",
+                samples: by_model(|stats| stats.prompts as f64),
+            },
+        ];
+        let waits = queue::wait_stats();
+        let by_kind = |value: fn(&WaitStats) -> f64| {
+            waits
+                .iter()
+                .map(|(model, kind, stats)| Sample {
+                    labels: vec![("model", model.clone()), ("kind", kind.as_str().to_owned())],
+                    value: value(stats),
+                })
+                .collect()
+        };
+        families.push(MetricFamily {
+            name: "llmserver_queue_wait_seconds_total",
+            kind: MetricKind::Counter,
+            help: "Time generations waited for their instance, by streaming or non-streaming request.",
+            samples: by_kind(|stats| stats.seconds),
+        });
+        families.push(MetricFamily {
+            name: "llmserver_queued_generations_total",
+            kind: MetricKind::Counter,
+            help: "Generations started by an instance, by streaming or non-streaming request.",
+            samples: by_kind(|stats| stats.jobs as f64),
+        });
+        if let Some(slo) = &self.slo {
+            families.push(MetricFamily {
+                name: "llmserver_slo_latency_seconds",
+                kind: MetricKind::Gauge,
+                help: "Response time percentile over the --slo-window-secs window, 0 until enough requests were seen.",
+                samples: vec![Sample::unlabeled(slo.current().map_or(0.0, |current| current.as_secs_f64()))],
+            });
+            families.push(MetricFamily {
+                name: "llmserver_slo_shed_total",
+                kind: MetricKind::Counter,
+                help: "Low priority requests rejected while the response time was over --slo-target-ms.",
+                samples: vec![Sample::unlabeled(slo.shed_count() as f64)],
+            });
+        }
+        families
+    }
+
+    /// Prometheus text exposition of every counter.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for family in self.families() {
+            let _ = writeln!(out, "# HELP {} {}", family.name, family.help);
+            let _ = writeln!(out, "# TYPE {} {}", family.name, family.kind.as_str());
+            for sample in family.samples {
+                let labels = sample
+                    .labels
+                    .iter()
+                    .map(|(key, value)| format!("{}=\"{}\"", key, value.replace('\\', "\\\\").replace('"', "\\\"")))
+                    .collect::<Vec<_>>();
+                if labels.is_empty() {
+                    let _ = writeln!(out, "{} {}", family.name, sample.value);
+                } else {
+                    let _ = writeln!(out, "{}{{{}}} {}", family.name, labels.join(","), sample.value);
+                }
+            }
+        }
+        out
+    }
+}
+
+/// Get server metrics in Prometheus text format.
+#[utoipa::path(
+    responses(
+        (status = OK, description = "Success", body = str, content_type = "text/plain")
+    )
+)]
+#[get("/metrics")]
+pub async fn metrics(metrics: web::Data<Metrics>) -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics.render())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn workers_share_one_set_of_counters() {
+        let shared = web::Data::new(Metrics::default());
+        // Every actix worker gets a clone of the same `Data`
+        let workers = (0..4)
+            .map(|_| {
+                let worker = shared.clone();
+                thread::spawn(move || {
+                    worker.count_chat_request();
+                    worker.count_transcription_request();
+                    for _ in 0..10 {
+                        worker.count_token();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        let rendered = shared.render();
+        assert!(rendered.contains("llmserver_chat_requests_total 4\n"));
+        assert!(rendered.contains("llmserver_transcription_requests_total 4\n"));
+        assert!(rendered.contains("llmserver_generated_tokens_total 40\n"));
+    }
+}
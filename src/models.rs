@@ -0,0 +1,310 @@
+use std::{collections::HashMap, fs::File, path::Path};
+
+use actix_web::{get, web, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    error::ApiError,
+    huggingface::ModelType,
+    response::{ListResponse, ObjectType},
+    OpenAiError,
+};
+
+/// What a text model declares it can handle, everything is off unless set in its config.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, utoipa::ToSchema)]
+#[serde(default)]
+pub struct Capabilities {
+    pub supports_tools: bool,
+    pub supports_vision: bool,
+    pub supports_reasoning: bool,
+}
+
+/// OpenAI model object.
+#[derive(Debug, Clone, Deserialize, Serialize, utoipa::ToSchema)]
+pub struct Model {
+    pub id: String,
+    #[schema(value_type = String)]
+    pub object: ObjectType,
+    pub created: u64,
+    pub owned_by: String,
+    /// Non-standard, only set for text models.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub capabilities: Option<Capabilities>,
+}
+
+/// Sampling parameters the model was initialized with.
+#[derive(Debug, Clone, Deserialize, Serialize, utoipa::ToSchema)]
+pub struct SamplingDefaults {
+    pub max_new_tokens: i32,
+    pub top_k: i32,
+    pub top_p: f32,
+    pub temperature: f32,
+    pub repeat_penalty: f32,
+    pub frequency_penalty: f32,
+    pub presence_penalty: f32,
+}
+
+/// Audio an ASR model expects, so clients can convert before uploading.
+#[derive(Debug, Clone, Deserialize, Serialize, utoipa::ToSchema)]
+pub struct AudioFormat {
+    pub sample_rate: u32,
+    pub channels: u16,
+    /// Sample encoding the model consumes, e.g. `s16` for 16-bit signed PCM.
+    pub sample_format: String,
+    /// Longer uploads are rejected with `invalid_audio`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_duration_secs: Option<f32>,
+    /// Containers `/audio/transcriptions` accepts.
+    pub upload_formats: Vec<String>,
+}
+
+/// Everything a client may want to configure itself against one model.
+/// Local file paths (model files, caches) are deliberately left out.
+#[derive(Debug, Clone, Deserialize, Serialize, utoipa::ToSchema)]
+pub struct ModelDetails {
+    #[serde(flatten)]
+    pub model: Model,
+    #[schema(value_type = String)]
+    pub r#type: ModelType,
+    pub instances: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context_length: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sampling: Option<SamplingDefaults>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub think: Option<bool>,
+    /// Commit sha of the Hugging Face repo that was loaded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub revision: Option<String>,
+    /// Sent with every completion, see [`system_fingerprint`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system_fingerprint: Option<String>,
+    /// Only set for ASR models.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audio: Option<AudioFormat>,
+}
+
+impl ModelDetails {
+    pub fn new(name: &str, repo: &str, r#type: ModelType) -> Self {
+        let created = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_secs();
+        ModelDetails {
+            model: Model {
+                id: name.to_owned(),
+                object: ObjectType::Model,
+                created,
+                owned_by: repo.split('/').next().unwrap_or_default().to_owned(),
+                capabilities: None,
+            },
+            r#type,
+            instances: 0,
+            context_length: None,
+            sampling: None,
+            think: None,
+            revision: None,
+            system_fingerprint: None,
+            audio: None,
+        }
+    }
+}
+
+/// One entry of the model listing in [`ModelNotFound`].
+#[derive(Debug, Clone, Deserialize, Serialize, utoipa::ToSchema)]
+pub struct AvailableModel {
+    pub id: String,
+    #[schema(value_type = String)]
+    pub r#type: ModelType,
+}
+
+/// 404 body for every endpoint taking a model name, lists what is served so
+/// clients and routers can pick another model or host.
+#[derive(Debug, Serialize, utoipa::ToSchema)]
+pub struct ModelNotFound {
+    #[serde(flatten)]
+    pub error: OpenAiError,
+    pub available_models: Vec<AvailableModel>,
+}
+
+/// Init errors of configured models none of whose instances loaded, by model
+/// name, so requests for them get a 503 explaining why instead of a 404.
+#[derive(Debug, Clone, Default)]
+pub struct LoadFailures(pub HashMap<String, String>);
+
+impl LoadFailures {
+    pub fn error(&self, name: &str) -> Option<ApiError> {
+        self.0.get(name).map(|message| ApiError::ModelLoadFailed {
+            model: name.to_owned(),
+            message: message.clone(),
+        })
+    }
+}
+
+/// The 404 for `name`, which is either unknown or loaded with a type other
+/// than `expected` (e.g. an ASR model sent to chat completions).
+pub fn model_not_found(
+    name: &str,
+    expected: Option<ModelType>,
+    registry: &HashMap<String, ModelDetails>,
+) -> ApiError {
+    let message = match (registry.get(name), expected) {
+        (Some(details), Some(expected)) if details.r#type != expected => format!(
+            "The model {} is a {} model and cannot be used with this endpoint.",
+            name,
+            serde_variant::to_variant_name(&details.r#type).unwrap_or_default()
+        ),
+        _ => format!("The model {} does not exist or you do not have access to it.", name),
+    };
+    let mut available_models = registry
+        .values()
+        .map(|details| AvailableModel {
+            id: details.model.id.clone(),
+            r#type: details.r#type,
+        })
+        .collect::<Vec<_>>();
+    available_models.sort_by(|a, b| a.id.cmp(&b.id));
+    ApiError::ModelNotFound {
+        message,
+        available_models,
+    }
+}
+
+/// Identifies the content of a model file. hf-hub stores files as blobs named
+/// by their etag, the sha256 for LFS files, so that name is used without
+/// reading gigabytes; files outside the hub cache are hashed.
+pub fn model_file_id(model_file: &Path) -> std::io::Result<String> {
+    let resolved = std::fs::canonicalize(model_file)?;
+    let is_blob = resolved
+        .parent()
+        .and_then(|dir| dir.file_name())
+        .is_some_and(|dir| dir == "blobs");
+    match resolved.file_name() {
+        Some(name) if is_blob => Ok(name.to_string_lossy().into_owned()),
+        _ => {
+            let mut hasher = Sha256::new();
+            std::io::copy(&mut File::open(&resolved)?, &mut hasher)?;
+            Ok(format!("{:x}", hasher.finalize()))
+        }
+    }
+}
+
+/// Derives an OpenAI style `fp_` fingerprint from the model file id and the
+/// config that shapes generation, so it only changes when one of them does.
+pub fn system_fingerprint(model_file_id: &str, generation_config: &impl Serialize) -> serde_json::Result<String> {
+    let mut hasher = Sha256::new();
+    hasher.update(model_file_id.as_bytes());
+    hasher.update(serde_json::to_vec(generation_config)?);
+    let digest = format!("{:x}", hasher.finalize());
+    Ok(format!("fp_{}", &digest[..10]))
+}
+
+/// List the models this server has loaded.
+#[utoipa::path(
+    responses(
+        (status = OK, description = "Success", body = ListResponse<Model>, content_type = "application/json")
+    ),
+)]
+#[get("/models")]
+pub async fn list_models(registry: web::Data<HashMap<String, ModelDetails>>) -> impl Responder {
+    let mut data = registry
+        .values()
+        .map(|details| details.model.clone())
+        .collect::<Vec<_>>();
+    data.sort_by(|a, b| a.id.cmp(&b.id));
+    HttpResponse::Ok().json(ListResponse::new(data))
+}
+
+/// Get detailed information about one loaded model.
+#[utoipa::path(
+    params(("name" = String, Path, description = "Model name")),
+    responses(
+        (status = OK, description = "Success", body = ModelDetails, content_type = "application/json"),
+        (status = NOT_FOUND, description = "Unknown model", body = ModelNotFound, content_type = "application/json"),
+        (status = SERVICE_UNAVAILABLE, description = "The model failed to load", body = OpenAiError, content_type = "application/json")
+    ),
+)]
+#[get("/models/{name}")]
+pub async fn retrieve_model(
+    name: web::Path<String>,
+    registry: web::Data<HashMap<String, ModelDetails>>,
+    load_failures: web::Data<LoadFailures>,
+) -> Result<HttpResponse, ApiError> {
+    match registry.get(name.as_str()) {
+        Some(details) => Ok(HttpResponse::Ok().json(details)),
+        None => Err(load_failures
+            .error(&name)
+            .unwrap_or_else(|| model_not_found(&name, None, &registry))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        test::{call_and_read_body_json, init_service, TestRequest},
+        App,
+    };
+
+    use super::*;
+
+    #[test]
+    fn models_are_model_objects() {
+        let details = ModelDetails::new("qwen", "Qwen/Qwen2.5-3B", ModelType::LLM);
+        assert_eq!(serde_json::to_value(&details).unwrap()["object"], "model");
+    }
+
+    #[actix_web::test]
+    async fn endpoints_answer_with_model_objects() {
+        let registry = HashMap::from([
+            ("qwen".to_owned(), ModelDetails::new("qwen", "Qwen/Qwen2.5-3B", ModelType::LLM)),
+            ("sensevoice".to_owned(), ModelDetails::new("sensevoice", "happyme531/SenseVoiceSmall", ModelType::ASR)),
+        ]);
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(registry))
+                .app_data(web::Data::new(LoadFailures::default()))
+                .service(web::scope("/v1").service(list_models).service(retrieve_model)),
+        )
+        .await;
+
+        let list: serde_json::Value = call_and_read_body_json(&app, TestRequest::get().uri("/v1/models").to_request()).await;
+        assert_eq!(list["object"], "list");
+        let data = list["data"].as_array().unwrap();
+        assert_eq!(data.len(), 2);
+        assert!(data.iter().all(|model| model["object"] == "model"));
+
+        let model: serde_json::Value =
+            call_and_read_body_json(&app, TestRequest::get().uri("/v1/models/qwen").to_request()).await;
+        assert_eq!(model["object"], "model");
+        assert_eq!(model["id"], "qwen");
+    }
+
+    #[test]
+    fn fingerprint_follows_file_and_config() {
+        let fingerprint = system_fingerprint("abc123", &(0.7, 4096, false)).unwrap();
+        assert!(fingerprint.starts_with("fp_"));
+        assert_eq!(fingerprint.len(), 13);
+        assert_eq!(fingerprint, system_fingerprint("abc123", &(0.7, 4096, false)).unwrap());
+        assert_ne!(fingerprint, system_fingerprint("abc123", &(0.8, 4096, false)).unwrap());
+        assert_ne!(fingerprint, system_fingerprint("def456", &(0.7, 4096, false)).unwrap());
+    }
+
+    #[test]
+    fn file_id_follows_content() {
+        let dir = std::env::temp_dir().join(format!("llmserver-file-id-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("blobs")).unwrap();
+        let model = dir.join("model.rkllm");
+        std::fs::write(&model, b"weights").unwrap();
+        let id = model_file_id(&model).unwrap();
+        assert_eq!(id, model_file_id(&model).unwrap());
+        std::fs::write(&model, b"other weights").unwrap();
+        assert_ne!(id, model_file_id(&model).unwrap());
+
+        // Hub cache blobs are named by their hash already
+        let blob = dir.join("blobs").join("0123abcd");
+        std::fs::write(&blob, b"weights").unwrap();
+        assert_eq!(model_file_id(&blob).unwrap(), "0123abcd");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,207 @@
+use serde::{Deserialize, Serialize};
+use zhconv::{zhconv, Variant};
+
+/// Target Chinese script/region for the output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, utoipa::ToSchema)]
+pub enum ChineseScript {
+    #[serde(rename = "zh-hans")]
+    Simplified,
+    #[serde(rename = "zh-hant")]
+    Traditional,
+    #[serde(rename = "zh-cn")]
+    Mainland,
+    #[serde(rename = "zh-tw")]
+    Taiwan,
+    #[serde(rename = "zh-hk")]
+    HongKong,
+}
+
+impl From<ChineseScript> for Variant {
+    fn from(script: ChineseScript) -> Self {
+        match script {
+            ChineseScript::Simplified => Variant::ZhHans,
+            ChineseScript::Traditional => Variant::ZhHant,
+            ChineseScript::Mainland => Variant::ZhCN,
+            ChineseScript::Taiwan => Variant::ZhTW,
+            ChineseScript::HongKong => Variant::ZhHK,
+        }
+    }
+}
+
+/// Target width for ASCII-range punctuation, letters and digits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, utoipa::ToSchema)]
+pub enum CharWidth {
+    #[serde(rename = "half")]
+    Half,
+    #[serde(rename = "full")]
+    Full,
+}
+
+/// Opt-in transform applied to every generated chunk before it is sent.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, utoipa::ToSchema)]
+pub struct OutputNormalization {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub script: Option<ChineseScript>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub width: Option<CharWidth>,
+}
+
+impl OutputNormalization {
+    pub fn is_noop(&self) -> bool {
+        self.script.is_none() && self.width.is_none()
+    }
+
+    pub fn apply(&self, text: &str) -> String {
+        let text = match self.script {
+            Some(script) => zhconv(text, script.into()),
+            None => text.to_owned(),
+        };
+        match self.width {
+            Some(CharWidth::Half) => text.chars().map(to_halfwidth).collect(),
+            Some(CharWidth::Full) => text.chars().map(to_fullwidth).collect(),
+            None => text,
+        }
+    }
+}
+
+fn to_halfwidth(c: char) -> char {
+    match c {
+        '\u{3000}' => ' ',
+        '\u{FF01}'..='\u{FF5E}' => char::from_u32(c as u32 - 0xFEE0).unwrap_or(c),
+        _ => c,
+    }
+}
+
+fn to_fullwidth(c: char) -> char {
+    match c {
+        ' ' => '\u{3000}',
+        '!'..='~' => char::from_u32(c as u32 + 0xFEE0).unwrap_or(c),
+        _ => c,
+    }
+}
+
+/// Cuts `text` back to its last complete sentence, for responses that stopped
+/// at a length limit. Finished lines count as complete, so list items and
+/// headings without punctuation are kept, and nothing inside a code fence is
+/// taken for a sentence end. Returns `text` unchanged when no boundary exists.
+pub fn trim_incomplete_sentence(text: &str) -> &str {
+    let mut end = 0;
+    let mut start = 0;
+    let mut in_code = false;
+    for line in text.split_inclusive('\n') {
+        let line_end = start + line.len();
+        if line.trim_start().starts_with("```") {
+            in_code = !in_code;
+            if !in_code {
+                end = line_end;
+            }
+        } else if !in_code && line.ends_with('\n') {
+            end = line_end;
+        } else if !in_code {
+            if let Some(at) = last_sentence_end(line) {
+                end = start + at;
+            }
+        }
+        start = line_end;
+    }
+    match text[..end].trim_end() {
+        "" => text,
+        trimmed => trimmed,
+    }
+}
+
+/// Byte offset just past the last sentence end in an unfinished line.
+fn last_sentence_end(line: &str) -> Option<usize> {
+    let body = line.trim_start();
+    let indent = line.len() - body.len();
+    // A list marker such as "2." is not a sentence end
+    let marker = match body.find(' ') {
+        Some(at) if is_list_marker(&body[..at]) => at,
+        _ => 0,
+    };
+    let scan = &body[marker..];
+    let mut last = None;
+    let mut chars = scan.char_indices().peekable();
+    while let Some((at, c)) = chars.next() {
+        let wide = matches!(c, '。' | '！' | '？' | '…');
+        if !(wide || matches!(c, '.' | '!' | '?')) {
+            continue;
+        }
+        // "Dr. Smith" and "e.g. this" go on after the period
+        if c == '.' && ends_with_abbreviation(&scan[..at]) {
+            continue;
+        }
+        let mut after = at + c.len_utf8();
+        // Closing quotes and brackets belong to the sentence they end
+        while let Some(&(next_at, next)) = chars.peek() {
+            if !matches!(next, '"' | '\'' | ')' | ']' | '”' | '’' | '」' | '』' | '）') {
+                break;
+            }
+            after = next_at + next.len_utf8();
+            chars.next();
+        }
+        // "3.14" and "e.g.x" are not sentence ends, CJK stops need no space
+        let followed_by_space = scan[after..].chars().next().is_none_or(char::is_whitespace);
+        if wide || followed_by_space {
+            last = Some(indent + marker + after);
+        }
+    }
+    last
+}
+
+/// Abbreviations that rarely end a sentence, without their last period.
+const ABBREVIATIONS: &[&str] = &["e.g", "i.e", "mr", "mrs", "ms", "dr", "prof", "st", "vs", "cf", "approx", "fig"];
+
+fn ends_with_abbreviation(text: &str) -> bool {
+    let word = text.rsplit(|c: char| c.is_whitespace() || c == '(').next().unwrap_or(text);
+    ABBREVIATIONS.iter().any(|abbreviation| word.eq_ignore_ascii_case(abbreviation))
+}
+
+fn is_list_marker(word: &str) -> bool {
+    matches!(word, "-" | "*" | "+")
+        || word
+            .strip_suffix(['.', ')'])
+            .is_some_and(|number| !number.is_empty() && number.chars().all(|c| c.is_ascii_digit()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cuts_after_the_last_sentence() {
+        assert_eq!(trim_incomplete_sentence("It rained. Then the sun came"), "It rained.");
+        assert_eq!(trim_incomplete_sentence("Really? Yes! And then"), "Really? Yes!");
+        assert_eq!(last_sentence_end("It rained. Then"), Some(10));
+    }
+
+    #[test]
+    fn abbreviations_do_not_end_sentences() {
+        assert_eq!(trim_incomplete_sentence("Ask Dr. Smith, e.g. on Monday. Or vs. the"), "Ask Dr. Smith, e.g. on Monday.");
+        assert_eq!(last_sentence_end("Pi is 3.14 and (e.g. more"), None);
+        assert_eq!(last_sentence_end("2. First step"), None);
+    }
+
+    #[test]
+    fn keeps_closing_quotes_and_brackets() {
+        assert_eq!(trim_incomplete_sentence(r#"He said "stop." Then"#), r#"He said "stop.""#);
+        assert_eq!(trim_incomplete_sentence("It works (mostly.) But"), "It works (mostly.)");
+        assert_eq!(trim_incomplete_sentence("她說「好。」然後"), "她說「好。」");
+    }
+
+    #[test]
+    fn no_sentence_end() {
+        assert_eq!(trim_incomplete_sentence("an unfinished thought"), "an unfinished thought");
+        assert_eq!(trim_incomplete_sentence(""), "");
+        // Finished lines count as complete
+        assert_eq!(trim_incomplete_sentence("- item one\n- item tw"), "- item one");
+        assert_eq!(trim_incomplete_sentence("Code:\n```\nlet x = 1. y\n```\nThen it"), "Code:\n```\nlet x = 1. y\n```");
+    }
+
+    #[test]
+    fn multibyte_text() {
+        assert_eq!(trim_incomplete_sentence("今天下雨。明天會"), "今天下雨。");
+        assert_eq!(trim_incomplete_sentence("Café ouvert. Crème brû"), "Café ouvert.");
+        assert_eq!(last_sentence_end("日本語！テ"), Some("日本語！".len()));
+    }
+}
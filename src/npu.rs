@@ -0,0 +1,71 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        OnceLock,
+    },
+    time::Instant,
+};
+
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+/// Limit on NPU jobs running at once across every model, set once from the
+/// command line. An LLM generation and an ASR transcription on the same
+/// accelerator can otherwise oversubscribe it and fail.
+static NPU_LIMIT: OnceLock<Semaphore> = OnceLock::new();
+
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+static WAITS: AtomicU64 = AtomicU64::new(0);
+static WAIT_MICROS: AtomicU64 = AtomicU64::new(0);
+
+/// Only the first call has an effect, later ones are ignored.
+pub fn set_npu_concurrency(limit: usize) {
+    let _ = NPU_LIMIT.set(Semaphore::new(limit));
+}
+
+/// One running NPU job, frees its slot when dropped.
+#[derive(Debug)]
+pub struct NpuPermit {
+    _permit: Option<SemaphorePermit<'static>>,
+}
+
+impl Drop for NpuPermit {
+    fn drop(&mut self) {
+        ACTIVE.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Waits for a free slot, immediately when no limit is set. Hold the permit
+/// for the whole blocking rkllm or sensevoice call.
+pub async fn acquire() -> NpuPermit {
+    let permit = match NPU_LIMIT.get() {
+        Some(semaphore) => Some(match semaphore.try_acquire() {
+            Ok(permit) => permit,
+            Err(_) => {
+                let waiting = Instant::now();
+                // The semaphore is never closed
+                let permit = semaphore.acquire().await.expect("NPU semaphore closed");
+                WAITS.fetch_add(1, Ordering::Relaxed);
+                WAIT_MICROS.fetch_add(waiting.elapsed().as_micros() as u64, Ordering::Relaxed);
+                permit
+            }
+        }),
+        None => None,
+    };
+    ACTIVE.fetch_add(1, Ordering::Relaxed);
+    NpuPermit { _permit: permit }
+}
+
+/// NPU jobs running right now.
+pub fn active() -> usize {
+    ACTIVE.load(Ordering::Relaxed)
+}
+
+/// Jobs that had to wait for a slot.
+pub fn waits() -> u64 {
+    WAITS.load(Ordering::Relaxed)
+}
+
+/// Total time jobs spent waiting for a slot.
+pub fn wait_seconds() -> f64 {
+    WAIT_MICROS.load(Ordering::Relaxed) as f64 / 1e6
+}
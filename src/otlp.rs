@@ -0,0 +1,83 @@
+use std::{sync::Arc, time::Duration};
+
+use opentelemetry::{metrics::MeterProvider, KeyValue};
+use opentelemetry_otlp::{MetricExporter, WithExportConfig};
+use opentelemetry_sdk::metrics::{PeriodicReader, SdkMeterProvider};
+
+use crate::metrics::{MetricKind, Metrics, Sample};
+
+/// Pushes [`Metrics`] to an OpenTelemetry collector until shut down.
+pub struct OtlpExporter {
+    provider: SdkMeterProvider,
+}
+
+impl OtlpExporter {
+    /// Exports every metric from [`Metrics::families`] over OTLP/HTTP to
+    /// `endpoint`, e.g. `http://collector:4318/v1/metrics`, every `interval`.
+    pub fn start(
+        metrics: Arc<Metrics>,
+        endpoint: &str,
+        interval: Duration,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let exporter = MetricExporter::builder()
+            .with_http()
+            .with_endpoint(endpoint)
+            .build()?;
+        let reader = PeriodicReader::builder(exporter).with_interval(interval).build();
+        let provider = SdkMeterProvider::builder().with_reader(reader).build();
+        let meter = provider.meter("llmserver-rs");
+
+        for family in metrics.families() {
+            let metrics = metrics.clone();
+            let name = family.name;
+            // Read on every export so the values match what /metrics would show
+            let samples = move || {
+                metrics
+                    .families()
+                    .into_iter()
+                    .find(|f| f.name == name)
+                    .map_or_else(Vec::new, |f| f.samples)
+            };
+            let attributes = |sample: &Sample| {
+                sample
+                    .labels
+                    .iter()
+                    .map(|(key, value)| KeyValue::new(*key, value.clone()))
+                    .collect::<Vec<_>>()
+            };
+            match family.kind {
+                MetricKind::Gauge => {
+                    meter
+                        .f64_observable_gauge(name)
+                        .with_description(family.help)
+                        .with_callback(move |observer| {
+                            for sample in samples() {
+                                observer.observe(sample.value, &attributes(&sample));
+                            }
+                        })
+                        .build();
+                }
+                MetricKind::Counter => {
+                    meter
+                        .f64_observable_counter(name)
+                        .with_description(family.help)
+                        .with_callback(move |observer| {
+                            for sample in samples() {
+                                observer.observe(sample.value, &attributes(&sample));
+                            }
+                        })
+                        .build();
+                }
+            }
+        }
+        println!("Exporting OTLP metrics to {} every {:?}", endpoint, interval);
+        Ok(OtlpExporter { provider })
+    }
+
+    /// Flushes the last readings.
+    pub fn shutdown(self) {
+        if let Err(e) = self.provider.shutdown() {
+            eprintln!("OTLP metrics shutdown failed: {}", e);
+        }
+    }
+}
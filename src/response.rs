@@ -0,0 +1,61 @@
+use serde::{Deserialize, Serialize};
+
+/// The OpenAI `object` discriminator, every response body must carry the right one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, utoipa::ToSchema)]
+pub enum ObjectType {
+    #[serde(rename = "chat.completion")]
+    ChatCompletion,
+    #[serde(rename = "chat.completion.chunk")]
+    ChatCompletionChunk,
+    #[serde(rename = "text_completion")]
+    TextCompletion,
+    #[serde(rename = "embedding")]
+    Embedding,
+    #[serde(rename = "model")]
+    Model,
+    #[serde(rename = "list")]
+    List,
+}
+
+/// OpenAI style list envelope.
+#[derive(Debug, Deserialize, Serialize, utoipa::ToSchema)]
+pub struct ListResponse<T> {
+    #[schema(value_type = String)]
+    pub object: ObjectType,
+    pub data: Vec<T>,
+}
+
+impl<T> ListResponse<T> {
+    pub fn new(data: Vec<T>) -> Self {
+        ListResponse {
+            object: ObjectType::List,
+            data,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn object_names() {
+        let names = [
+            (ObjectType::ChatCompletion, "chat.completion"),
+            (ObjectType::ChatCompletionChunk, "chat.completion.chunk"),
+            (ObjectType::TextCompletion, "text_completion"),
+            (ObjectType::Embedding, "embedding"),
+            (ObjectType::Model, "model"),
+            (ObjectType::List, "list"),
+        ];
+        for (object, name) in names {
+            assert_eq!(serde_json::to_value(object).unwrap(), name);
+        }
+    }
+
+    #[test]
+    fn lists_are_list_objects() {
+        let list = serde_json::to_value(ListResponse::new(vec![1, 2])).unwrap();
+        assert_eq!(list, serde_json::json!({"object": "list", "data": [1, 2]}));
+    }
+}
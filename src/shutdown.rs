@@ -0,0 +1,101 @@
+use std::sync::{Arc, Mutex, PoisonError};
+
+/// Set once a native model handle is destroyed and held by every job using
+/// it, so shutdown waits for in-flight work and never frees a busy handle.
+#[derive(Debug, Clone, Default)]
+pub struct HandleGuard(Arc<Mutex<bool>>);
+
+impl HandleGuard {
+    /// Runs `job` unless the handle is destroyed, `destroy` waits for it.
+    pub fn run<T>(&self, job: impl FnOnce() -> T) -> Option<T> {
+        // A panicking job poisons the lock, the handle itself is still there
+        let destroyed = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        if *destroyed {
+            return None;
+        }
+        Some(job())
+    }
+
+    /// Waits for the running job, then calls `destroy` unless an earlier
+    /// call already did.
+    pub fn destroy(&self, destroy: impl FnOnce()) {
+        let mut destroyed = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        if !*destroyed {
+            destroy();
+            *destroyed = true;
+        }
+    }
+
+    /// Never blocks. A running job holds the lock, which means not destroyed.
+    pub fn is_destroyed(&self) -> bool {
+        self.0.try_lock().is_ok_and(|destroyed| *destroyed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            mpsc,
+        },
+        thread,
+        time::Duration,
+    };
+
+    use super::*;
+
+    #[test]
+    fn destroy_waits_for_a_running_job() {
+        let guard = HandleGuard::default();
+        let finished = Arc::new(AtomicBool::new(false));
+        let (started_tx, started) = mpsc::channel();
+        let job = {
+            let guard = guard.clone();
+            let finished = finished.clone();
+            thread::spawn(move || {
+                guard.run(|| {
+                    started_tx.send(()).unwrap();
+                    // A generation still running when shutdown arrives
+                    thread::sleep(Duration::from_millis(100));
+                    finished.store(true, Ordering::SeqCst);
+                })
+            })
+        };
+        started.recv().unwrap();
+        assert!(!guard.is_destroyed());
+        guard.destroy(|| assert!(finished.load(Ordering::SeqCst), "destroyed a busy handle"));
+        assert!(guard.is_destroyed());
+        assert_eq!(job.join().unwrap(), Some(()));
+    }
+
+    #[test]
+    fn jobs_after_destroy_do_not_run() {
+        let guard = HandleGuard::default();
+        guard.destroy(|| {});
+        assert_eq!(guard.run(|| panic!("ran on a destroyed handle")), None::<()>);
+    }
+
+    #[test]
+    fn destroy_only_once() {
+        let guard = HandleGuard::default();
+        let calls = AtomicUsize::new(0);
+        guard.destroy(|| {
+            calls.fetch_add(1, Ordering::SeqCst);
+        });
+        guard.destroy(|| {
+            calls.fetch_add(1, Ordering::SeqCst);
+        });
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn survives_a_panicking_job() {
+        let guard = HandleGuard::default();
+        let panicking = guard.clone();
+        assert!(thread::spawn(move || panicking.run(|| panic!("generation failed"))).join().is_err());
+        let destroyed = AtomicBool::new(false);
+        guard.destroy(|| destroyed.store(true, Ordering::SeqCst));
+        assert!(destroyed.load(Ordering::SeqCst));
+    }
+}
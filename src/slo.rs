@@ -0,0 +1,96 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Fewer samples than this in the window never count as a violation, so a
+/// single slow request after a quiet period does not start shedding.
+const MIN_SAMPLES: usize = 20;
+
+/// Non-standard request priority, only `low` requests are shed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, utoipa::ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+}
+
+/// Response time target such as "p99 under 2s" over a rolling window. The
+/// response time is how long a request waited for its first byte: the whole
+/// response when not streaming, the first chunk when streaming.
+#[derive(Debug)]
+pub struct Slo {
+    target: Duration,
+    percentile: f64,
+    window: Duration,
+    samples: Mutex<VecDeque<(Instant, Duration)>>,
+    shed: AtomicU64,
+}
+
+impl Slo {
+    /// `percentile` is in (0, 100].
+    pub fn new(target: Duration, percentile: f64, window: Duration) -> Self {
+        Slo {
+            target,
+            percentile,
+            window,
+            samples: Mutex::new(VecDeque::new()),
+            shed: AtomicU64::new(0),
+        }
+    }
+
+    pub fn target(&self) -> Duration {
+        self.target
+    }
+
+    pub fn percentile(&self) -> f64 {
+        self.percentile
+    }
+
+    pub fn record(&self, latency: Duration) {
+        let now = Instant::now();
+        let mut samples = self.samples.lock().unwrap();
+        samples.push_back((now, latency));
+        Self::prune(&mut samples, now, self.window);
+    }
+
+    fn prune(samples: &mut VecDeque<(Instant, Duration)>, now: Instant, window: Duration) {
+        while samples.front().is_some_and(|(at, _)| now.duration_since(*at) > window) {
+            samples.pop_front();
+        }
+    }
+
+    /// The configured percentile of the window, `None` with too few samples.
+    pub fn current(&self) -> Option<Duration> {
+        let mut samples = self.samples.lock().unwrap();
+        Self::prune(&mut samples, Instant::now(), self.window);
+        if samples.len() < MIN_SAMPLES {
+            return None;
+        }
+        let mut latencies = samples.iter().map(|(_, latency)| *latency).collect::<Vec<_>>();
+        latencies.sort();
+        let rank = (self.percentile / 100.0 * latencies.len() as f64).ceil() as usize;
+        Some(latencies[rank.clamp(1, latencies.len()) - 1])
+    }
+
+    /// Whether a request of `priority` should be rejected right now, counted
+    /// as shed when it is.
+    pub fn should_shed(&self, priority: Priority) -> bool {
+        let shed = priority == Priority::Low && self.current().is_some_and(|current| current > self.target);
+        if shed {
+            self.shed.fetch_add(1, Ordering::Relaxed);
+        }
+        shed
+    }
+
+    pub fn shed_count(&self) -> u64 {
+        self.shed.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,124 @@
+use std::{
+    io,
+    os::{
+        linux::net::SocketAddrExt,
+        unix::net::{SocketAddr, UnixDatagram},
+    },
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use actix::Recipient;
+
+use crate::Ping;
+
+/// Sends `sd_notify` messages to systemd. Only exists when the server was
+/// started by systemd with `NOTIFY_SOCKET` set, e.g. under `Type=notify`.
+#[derive(Debug)]
+pub struct Notifier {
+    socket: UnixDatagram,
+    addr: SocketAddr,
+    /// Half of `WatchdogSec`, as systemd recommends. `None` without a watchdog.
+    watchdog_interval: Option<Duration>,
+}
+
+impl Notifier {
+    pub fn from_env() -> io::Result<Option<Self>> {
+        let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+            return Ok(None);
+        };
+        let path = path.to_string_lossy();
+        // A leading `@` names a socket in the abstract namespace
+        let addr = match path.strip_prefix('@') {
+            Some(name) => SocketAddr::from_abstract_name(name)?,
+            None => SocketAddr::from_pathname(path.as_ref())?,
+        };
+        // The watchdog is meant for the main process only
+        let for_us = std::env::var("WATCHDOG_PID")
+            .map_or(true, |pid| pid.parse() == Ok(std::process::id()));
+        let watchdog_interval = std::env::var("WATCHDOG_USEC")
+            .ok()
+            .and_then(|usec| usec.parse::<u64>().ok())
+            .filter(|&usec| usec > 0 && for_us)
+            .map(|usec| Duration::from_micros(usec / 2));
+        Ok(Some(Notifier {
+            socket: UnixDatagram::unbound()?,
+            addr,
+            watchdog_interval,
+        }))
+    }
+
+    pub fn watchdog_interval(&self) -> Option<Duration> {
+        self.watchdog_interval
+    }
+
+    /// Sends one state line such as `READY=1`. Failures are only logged, the
+    /// server must keep running whatever happens to the notify socket.
+    pub fn notify(&self, state: &str) {
+        if let Err(e) = self.socket.send_to_addr(state.as_bytes(), &self.addr) {
+            eprintln!("Failed to notify systemd ({}): {}", state, e);
+        }
+    }
+
+    /// Keeps feeding the watchdog from a thread until the guard is dropped,
+    /// for reloads, which block the runtime the health checks run on.
+    pub fn keep_alive(self: &Arc<Self>) -> KeepAlive {
+        let stop = Arc::new(AtomicBool::new(false));
+        if let Some(interval) = self.watchdog_interval {
+            let notifier = self.clone();
+            let stop = stop.clone();
+            std::thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    notifier.notify("WATCHDOG=1");
+                    std::thread::sleep(interval);
+                }
+            });
+        }
+        KeepAlive(stop)
+    }
+}
+
+/// Stops the thread started by [`Notifier::keep_alive`] when dropped.
+#[derive(Debug)]
+pub struct KeepAlive(Arc<AtomicBool>);
+
+impl Drop for KeepAlive {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Pings every model instance once per watchdog interval and feeds the
+/// watchdog only when all of them answered healthy in time. A hung or dead
+/// instance therefore lets `WatchdogSec` run out and systemd restarts us.
+pub async fn watch(notifier: Arc<Notifier>, instances: Vec<(String, Recipient<Ping>)>) {
+    let Some(interval) = notifier.watchdog_interval else {
+        return;
+    };
+    // Pings get half the interval, so a slow answer still leaves time to notify
+    let ping_timeout = interval / 2;
+    let mut ticks = actix_web::rt::time::interval(interval);
+    loop {
+        ticks.tick().await;
+        let pings = instances.iter().map(|(model, instance)| async move {
+            match actix_web::rt::time::timeout(ping_timeout, instance.send(Ping)).await {
+                Ok(Ok(true)) => true,
+                Ok(Ok(false)) | Ok(Err(_)) => {
+                    eprintln!("Watchdog: an instance of {} is dead", model);
+                    false
+                }
+                Err(_) => {
+                    eprintln!("Watchdog: an instance of {} did not answer within {:?}", model, ping_timeout);
+                    false
+                }
+            }
+        });
+        let healthy = futures::future::join_all(pings).await.into_iter().all(|alive| alive);
+        if healthy {
+            notifier.notify("WATCHDOG=1");
+        }
+    }
+}